@@ -0,0 +1,54 @@
+use std::env;
+use anyhow::{anyhow, Result};
+use npc::{parse_flags, parse_game_flag, parse_value, NeatConfig, TrainConfig};
+
+/// 训练程序命令行参数
+///
+/// npc [--output 模型文件] [--generations 代数] [--population 种群大小] [--seed 随机数种子] [--threads 线程数]
+///
+/// 比赛参数: --group 每场NPC数量 --rounds 每个个体的比赛场数 --ticks 每场最多移动周期 --hunger 饿死的移动周期
+///
+/// 场地参数: --width --height --max-foods --start-length
+#[derive(Debug, Clone)]
+pub struct TrainArgs{
+    /// 最优模型保存的位置
+    pub output: String,
+    pub generations: usize,
+    pub seed: u64,
+    pub neat: NeatConfig,
+    pub train: TrainConfig,
+}
+
+impl TrainArgs{
+    pub fn parse() -> Result<Self>{
+        let mut output = "npc_model.json".to_string();
+        let mut generations = 100;
+        let mut seed = rand::random();
+        let mut neat = NeatConfig::default();
+        let mut train = TrainConfig::default();
+
+        parse_flags(env::args().skip(1), |name, value|{
+            match name{
+                "--output" => output = value.to_string(),
+                "--generations" => generations = parse_value(name, value)?,
+                "--population" => neat.population_size = parse_value(name, value)?,
+                "--seed" => seed = parse_value(name, value)?,
+                "--threads" => train.threads = parse_value(name, value)?,
+                "--group" => train.group_size = parse_value(name, value)?,
+                "--rounds" => train.rounds = parse_value(name, value)?,
+                "--ticks" => train.max_ticks = parse_value(name, value)?,
+                "--hunger" => train.hunger_ticks = parse_value(name, value)?,
+                _ => return parse_game_flag(&mut train.game, name, value),
+            }
+            Ok(true)
+        })?;
+        train.game.validate()?;
+        if neat.population_size < 2 || train.group_size == 0 || train.rounds == 0{
+            return Err(anyhow!("种群大小至少为2, 每场NPC数量和比赛场数至少为1"));
+        }
+        // 一场比赛的NPC都要能加入游戏
+        train.game.max_players = train.game.max_players.max(train.group_size);
+
+        Ok(Self{ output, generations, seed, neat, train })
+    }
+}
@@ -0,0 +1,108 @@
+//! 强化学习环境服务
+//!
+//! 通过标准输入输出和外部脚本通信, 每行一个json:
+//!
+//! - `{"cmd":"spec"}` 返回观察长度、动作数量、局部网格边长
+//! - `{"cmd":"reset","seed":1}` 开始新的一局, seed可以省略
+//! - `{"cmd":"step","action":0}` 执行动作(0上 1下 2左 3右), 返回观察、奖励和是否结束
+//! - `{"cmd":"close"}` 退出, 标准输入关闭时也会退出
+//!
+//! 出错时返回 `{"error":"原因"}`。
+//!
+//! gym [--opponents 逗号分隔的对手大脑] [--ticks 每局最多移动周期] [--hunger 饿死的移动周期] [--grid 局部网格半径]
+//! [--width --height --max-foods --start-length]
+
+use std::{env, io::{self, BufRead, BufWriter, Write}};
+
+use anyhow::{anyhow, Result};
+use npc::{parse_flags, parse_game_flag, parse_value, unknown_opponent, Env, EnvConfig};
+use serde::{Deserialize, Serialize};
+use snake::OBSERVATION_SIZE;
+
+#[derive(Deserialize)]
+#[serde(tag = "cmd", rename_all = "snake_case")]
+enum Request{
+    Spec,
+    Reset{ seed: Option<u64> },
+    Step{ action: usize },
+    Close,
+}
+
+#[derive(Serialize)]
+struct Spec{
+    observation_size: usize,
+    actions: usize,
+    /// 局部网格边长, 没有局部网格时为0
+    grid_size: i32,
+}
+
+#[derive(Serialize)]
+struct Error{
+    error: String,
+}
+
+fn main() {
+    let config = match parse_args(){
+        Err(err) => {
+            eprintln!("{err}");
+            std::process::exit(1);
+        }
+        Ok(v) => v
+    };
+    let mut env = Env::new(config);
+    let mut episode = 0u64;
+
+    let stdin = io::stdin();
+    let mut stdout = BufWriter::new(io::stdout().lock());
+    for line in stdin.lock().lines(){
+        let line = match line{
+            Ok(v) => v,
+            Err(_) => break
+        };
+        if line.trim().is_empty(){
+            continue;
+        }
+        let response = match serde_json::from_str::<Request>(&line){
+            Err(err) => serde_json::to_string(&Error{ error: format!("无法解析请求: {err}") }),
+            Ok(Request::Close) => break,
+            Ok(Request::Spec) => serde_json::to_string(&Spec{
+                observation_size: OBSERVATION_SIZE,
+                actions: Env::action_count(),
+                grid_size: if env.config.grid_radius > 0 { env.config.grid_radius * 2 + 1 } else { 0 },
+            }),
+            Ok(Request::Reset{ seed }) => {
+                // 没有指定种子时每局使用不同的种子
+                episode += 1;
+                serde_json::to_string(&env.reset(seed.unwrap_or(episode)))
+            }
+            Ok(Request::Step{ action }) => serde_json::to_string(&env.step(action)),
+        };
+        let written = response.map_err(io::Error::from)
+            .and_then(|text| writeln!(stdout, "{text}"))
+            .and_then(|_| stdout.flush());
+        if written.is_err(){
+            break;
+        }
+    }
+}
+
+fn parse_args() -> Result<EnvConfig>{
+    let mut config = EnvConfig::default();
+    parse_flags(env::args().skip(1), |name, value|{
+        match name{
+            "--opponents" => config.opponents = value.split(',').map(|name| name.trim().to_string()).filter(|name| !name.is_empty()).collect(),
+            "--ticks" => config.max_ticks = parse_value(name, value)?,
+            "--hunger" => config.hunger_ticks = parse_value(name, value)?,
+            "--grid" => config.grid_radius = parse_value(name, value)?,
+            _ => return parse_game_flag(&mut config.game, name, value),
+        }
+        Ok(true)
+    })?;
+    config.game.validate()?;
+    if let Some(name) = unknown_opponent(&config.opponents){
+        return Err(anyhow!("未知的对手大脑: {name}, 可以使用 random, greedy, floodfill, astar"));
+    }
+    // 智能体和所有对手都要能加入游戏
+    config.game.max_players = config.game.max_players.max(config.opponents.len() + 1);
+    Ok(config)
+}
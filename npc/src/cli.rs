@@ -0,0 +1,36 @@
+//! 训练程序和gym共用的命令行参数解析
+
+use std::{fmt::Debug, str::FromStr};
+
+use anyhow::{anyhow, Result};
+use snake::GameConfig;
+
+/// 依次处理`--参数 值`形式的命令行参数
+///
+/// handle返回false表示不认识这个参数
+pub fn parse_flags(args: impl IntoIterator<Item = String>, mut handle: impl FnMut(&str, &str) -> Result<bool>) -> Result<()>{
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next(){
+        let value = args.next().ok_or(anyhow!("{arg}缺少参数"))?;
+        if !handle(&arg, &value)?{
+            return Err(anyhow!("未知参数: {arg}"));
+        }
+    }
+    Ok(())
+}
+
+/// 场地参数: --width --height --max-foods --start-length, 不是场地参数时返回false
+pub fn parse_game_flag(game: &mut GameConfig, name: &str, value: &str) -> Result<bool>{
+    match name{
+        "--width" => game.arena_width = parse_value(name, value)?,
+        "--height" => game.arena_height = parse_value(name, value)?,
+        "--max-foods" => game.max_foods = parse_value(name, value)?,
+        "--start-length" => game.start_length = parse_value(name, value)?,
+        _ => return Ok(false),
+    }
+    Ok(true)
+}
+
+pub fn parse_value<T: FromStr>(name: &str, value: &str) -> Result<T> where T::Err: Debug{
+    value.parse().map_err(|err| anyhow!("{name}参数错误 {value}: {:?}", err))
+}
@@ -0,0 +1,270 @@
+//! 强化学习环境
+//!
+//! 和常见的gym环境一样提供reset/step: 一个由外部控制的智能体和若干个内置大脑控制的对手在同一个场地中游戏,
+//! 每一步返回观察、奖励和是否结束。规则就是服务器使用的[`GameState`]。
+
+use serde::Serialize;
+use snake::{builtin_brain, GameConfig, GameEvent, GameRng, GameState, SnakeBrain, WorldView, OBSERVATION_SIZE, DIRECTIONS};
+
+/// 吃到一个食物的奖励
+pub const FOOD_REWARD: f32 = 1.;
+/// 撞死一条蛇的奖励
+pub const KILL_REWARD: f32 = 0.5;
+/// 死亡(包括饿死)的奖励
+pub const DEATH_REWARD: f32 = -1.;
+
+/// 智能体的玩家id
+const AGENT_ID: &str = "agent";
+
+#[derive(Debug, Clone)]
+pub struct EnvConfig{
+    pub game: GameConfig,
+    /// 对手使用的内置大脑, 每个一条蛇
+    pub opponents: Vec<String>,
+    /// 一局最多进行的移动周期, 超过时结束(truncated)
+    pub max_ticks: u64,
+    /// 连续这么多个移动周期没有吃到食物的智能体饿死
+    pub hunger_ticks: u64,
+    /// 大于0时观察中附带以蛇头为中心的局部网格
+    pub grid_radius: i32,
+}
+
+impl Default for EnvConfig{
+    fn default() -> Self {
+        Self{
+            game: GameConfig::default(),
+            opponents: vec![],
+            max_ticks: 2000,
+            hunger_ticks: 200,
+            grid_radius: 0,
+        }
+    }
+}
+
+/// 每一步的结果
+#[derive(Debug, Clone, Serialize)]
+pub struct StepResult{
+    pub observation: Vec<f32>,
+    /// 局部网格, 没有设置grid_radius时为None
+    pub grid: Option<Vec<f32>>,
+    pub reward: f32,
+    /// 智能体死亡
+    pub done: bool,
+    /// 到达最大移动周期
+    pub truncated: bool,
+    pub info: StepInfo,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct StepInfo{
+    pub tick: u64,
+    pub length: usize,
+    pub kills: u32,
+}
+
+pub struct Env{
+    pub config: EnvConfig,
+    world: GameState,
+    opponents: Vec<(String, Box<dyn SnakeBrain>)>,
+    last_eaten: u64,
+    /// 智能体死亡时最后一次的观察, 之后一直返回它
+    last_observation: StepResult,
+}
+
+impl Env{
+    pub fn new(config: EnvConfig) -> Self{
+        let world = GameState::new(config.game.clone(), GameRng::new(0));
+        let mut env = Self{
+            config,
+            world,
+            opponents: vec![],
+            last_eaten: 0,
+            last_observation: StepResult{ observation: vec![0.; OBSERVATION_SIZE], grid: None, reward: 0., done: true, truncated: false, info: StepInfo::default() },
+        };
+        env.reset(0);
+        env
+    }
+
+    /// 动作的数量, 动作i对应DIRECTIONS[i]
+    pub fn action_count() -> usize{
+        DIRECTIONS.len()
+    }
+
+    /// 开始新的一局
+    pub fn reset(&mut self, seed: u64) -> StepResult{
+        self.world = GameState::new(self.config.game.clone(), GameRng::new(seed));
+        // 对手名字不存在时在解析参数的时候就会报错, 这里直接跳过
+        self.opponents = self.config.opponents.iter().enumerate()
+            .filter_map(|(idx, name)| builtin_brain(name, seed.wrapping_add(idx as u64 + 1)).map(|brain| (format!("{name}-{idx}"), brain)))
+            .collect();
+        let _ = self.world.add_player(AGENT_ID.to_string(), AGENT_ID.to_string());
+        for (player_id, _) in self.opponents.iter(){
+            let _ = self.world.add_player(player_id.clone(), player_id.clone());
+        }
+        while self.world.spawn_food().is_some(){}
+        self.last_eaten = 0;
+        self.last_observation = self.observe(0., false);
+        self.last_observation.clone()
+    }
+
+    /// 智能体执行动作, 推进一个移动周期
+    ///
+    /// 动作超出范围时保持原来的方向; 一局结束以后继续调用直接返回结束时的结果, 奖励为0
+    pub fn step(&mut self, action: usize) -> StepResult{
+        if self.last_observation.done || self.last_observation.truncated{
+            let mut result = self.last_observation.clone();
+            result.reward = 0.;
+            return result;
+        }
+
+        let mut inputs = vec![];
+        if let Some(direction) = DIRECTIONS.get(action){
+            inputs.push((AGENT_ID.to_string(), *direction));
+        }
+        {
+            let view = WorldView::new(&self.world);
+            for (player_id, brain) in self.opponents.iter_mut(){
+                if let Some(direction) = brain.think(&view, player_id){
+                    inputs.push((player_id.clone(), direction));
+                }
+            }
+        }
+
+        let mut reward = 0.;
+        let mut dead = false;
+        for event in self.world.step(&inputs){
+            match event{
+                GameEvent::FoodEaten { player_id, .. } if player_id == AGENT_ID => {
+                    reward += FOOD_REWARD;
+                    self.last_eaten = self.world.tick;
+                }
+                GameEvent::PlayerDied(death) => {
+                    if death.player_id == AGENT_ID{
+                        reward += DEATH_REWARD;
+                        dead = true;
+                    }else if death.killer.as_deref() == Some(AGENT_ID) && death.killer_kills > 0{
+                        reward += KILL_REWARD;
+                    }
+                }
+                _ => ()
+            }
+        }
+        if !dead && self.world.tick - self.last_eaten > self.config.hunger_ticks{
+            self.world.remove_player(AGENT_ID);
+            reward += DEATH_REWARD;
+            dead = true;
+        }
+
+        // 死亡的对手马上重新加入, 场地上的对手数量保持不变
+        let missing = self.opponents.iter()
+            .map(|(player_id, _)| player_id)
+            .filter(|player_id| !self.world.snakes.contains_key(*player_id))
+            .cloned()
+            .collect::<Vec<String>>();
+        for player_id in missing{
+            let _ = self.world.add_player(player_id.clone(), player_id);
+        }
+
+        let truncated = !dead && self.world.tick >= self.config.max_ticks;
+        if dead{
+            // 蛇已经删除, 观察沿用上一步的
+            let mut result = self.last_observation.clone();
+            result.reward = reward;
+            result.done = true;
+            result.info.tick = self.world.tick;
+            self.last_observation = result.clone();
+            return result;
+        }
+        self.last_observation = self.observe(reward, truncated);
+        self.last_observation.clone()
+    }
+
+    fn observe(&self, reward: f32, truncated: bool) -> StepResult{
+        let view = WorldView::new(&self.world);
+        let snake = self.world.snakes.get(AGENT_ID);
+        let grid = match self.config.grid_radius > 0{
+            true => view.observe_grid(AGENT_ID, self.config.grid_radius),
+            false => None,
+        };
+        StepResult{
+            observation: view.observe(AGENT_ID).map(|features| features.to_vec()).unwrap_or_else(|| vec![0.; OBSERVATION_SIZE]),
+            grid,
+            reward,
+            done: snake.is_none(),
+            truncated,
+            info: StepInfo{
+                tick: self.world.tick,
+                length: snake.map_or(0, |snake| snake.body.len()),
+                kills: snake.map_or(0, |snake| snake.kills),
+            },
+        }
+    }
+}
+
+/// 对手的名字都是内置大脑时返回None, 否则返回第一个不认识的名字
+pub fn unknown_opponent(opponents: &[String]) -> Option<&String>{
+    opponents.iter().find(|name| builtin_brain(name, 0).is_none())
+}
+
+
+#[cfg(test)]
+mod tests{
+    use snake::{Direction, Position};
+
+    use super::*;
+
+    fn env(opponents: &[&str]) -> Env{
+        let game = GameConfig{ arena_width: 10, arena_height: 10, max_foods: 1, ..Default::default() };
+        Env::new(EnvConfig{ game, opponents: opponents.iter().map(|name| name.to_string()).collect(), ..Default::default() })
+    }
+
+    /// 把智能体放到指定的位置, 清空食物
+    fn place_agent(env: &mut Env, body: &[(i32, i32)], direction: Direction){
+        let snake = env.world.snakes.get_mut(AGENT_ID).unwrap();
+        snake.body = body.iter().map(|(x, y)| Position::new(*x, *y)).collect();
+        snake.direction = direction;
+        env.world.foods.clear();
+    }
+
+    fn action(direction: Direction) -> usize{
+        DIRECTIONS.iter().position(|other| *other == direction).unwrap()
+    }
+
+    #[test]
+    fn eating_food_is_rewarded(){
+        let mut env = env(&[]);
+        env.reset(1);
+        place_agent(&mut env, &[(5, 5), (5, 4)], Direction::Up);
+        env.world.foods.push(Position::new(5, 6));
+        let result = env.step(action(Direction::Up));
+        assert_eq!(result.reward, FOOD_REWARD);
+        assert!(!result.done);
+    }
+
+    #[test]
+    fn death_ends_the_episode(){
+        let mut env = env(&[]);
+        env.reset(1);
+        place_agent(&mut env, &[(5, 9), (5, 8)], Direction::Up);
+        let result = env.step(action(Direction::Up));
+        assert_eq!(result.reward, DEATH_REWARD);
+        assert!(result.done);
+        // 结束以后继续调用只返回结束的结果
+        let result = env.step(action(Direction::Left));
+        assert_eq!(result.reward, 0.);
+        assert!(result.done);
+    }
+
+    #[test]
+    fn same_seed_gives_the_same_episode(){
+        let run = ||{
+            let mut env = env(&["random", "greedy", "astar"]);
+            let mut results = vec![format!("{:?}", env.reset(42))];
+            for idx in 0..100{
+                results.push(format!("{:?}", env.step(idx / 5 % Env::action_count())));
+            }
+            results
+        };
+        assert_eq!(run(), run());
+    }
+}
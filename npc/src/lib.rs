@@ -0,0 +1,18 @@
+//! NPC: 用NEAT算法进化的神经网络控制的蛇
+//!
+//! 训练程序(`npc`)在模拟的游戏中进化种群, 把最优的基因组保存成模型文件,
+//! 服务器加载模型文件控制NPC。`gym`程序通过标准输入输出把游戏提供给外部的强化学习脚本。
+
+pub mod cli;
+pub mod env;
+pub mod model;
+pub mod neat;
+pub mod sensor;
+pub mod trainer;
+
+pub use cli::*;
+pub use env::*;
+pub use model::*;
+pub use neat::*;
+pub use sensor::*;
+pub use trainer::*;
@@ -1,3 +1,53 @@
+//! NPC训练程序
+//!
+//! 只使用CPU, 不需要网络。每一代结束后如果出现了更好的个体, 就把它保存到模型文件,
+//! 训练中途停止也可以直接使用已经保存的模型。
+
+use std::time::Instant;
+
+use npc::*;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+
+mod args;
+use args::TrainArgs;
+
 fn main() {
-    println!("Hello, world!");
+    let args = match TrainArgs::parse(){
+        Err(err) => {
+            eprintln!("{err}");
+            std::process::exit(1);
+        }
+        Ok(v) => v
+    };
+    println!("开始训练: 种子:{} 种群:{} 代数:{} 线程:{} 模型文件:{}",
+        args.seed, args.neat.population_size, args.generations, args.train.threads, args.output);
+
+    let mut rng = ChaCha8Rng::seed_from_u64(args.seed);
+    let mut population = Population::new(args.neat.clone(), INPUTS, OUTPUTS, &mut rng);
+    let mut best_fitness = f32::MIN;
+
+    for _ in 0..args.generations{
+        let start = Instant::now();
+        evaluate(&mut population, &args.train, rng.gen());
+
+        let best = population.genomes.iter().max_by(|a, b| a.fitness.total_cmp(&b.fitness)).unwrap().clone();
+        let average = population.genomes.iter().map(|genome| genome.fitness).sum::<f32>() / population.genomes.len() as f32;
+        let generation = population.generation;
+        if best.fitness > best_fitness{
+            best_fitness = best.fitness;
+            match Model::new(best.clone(), generation).save(&args.output){
+                Ok(()) => println!("保存模型 {}", args.output),
+                Err(err) => eprintln!("模型保存失败: {:?}", err),
+            }
+        }
+
+        // 进化时才划分物种, 所以物种数量在进化之后输出
+        population.evolve(&mut rng);
+        println!("第{generation}代 最高:{:.1} 平均:{:.1} 物种:{} 节点:{} 连接:{} 用时:{:.1}秒",
+            best.fitness, average, population.species_count(),
+            best.nodes.len(), best.connections.iter().filter(|connection| connection.enabled).count(),
+            start.elapsed().as_secs_f32());
+    }
+    println!("训练结束, 最高适应度:{:.1}", best_fitness);
 }
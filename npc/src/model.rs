@@ -0,0 +1,57 @@
+//! 模型文件
+//!
+//! 训练程序把最优的基因组保存成json, 服务器加载后生成网络控制NPC。
+
+use std::fs;
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{neat::{Genome, Network}, sensor::{INPUTS, OUTPUTS}};
+
+/// 模型文件格式版本, 输入输出的含义改变时加1
+pub const MODEL_VERSION: u32 = 2;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Model{
+    pub version: u32,
+    /// 训练到第几代
+    pub generation: usize,
+    pub fitness: f32,
+    pub genome: Genome,
+}
+
+impl Model{
+    pub fn new(genome: Genome, generation: usize) -> Self{
+        Self{
+            version: MODEL_VERSION,
+            generation,
+            fitness: genome.fitness,
+            genome,
+        }
+    }
+
+    pub fn load(path: &str) -> Result<Self>{
+        let text = fs::read_to_string(path).map_err(|err| anyhow!("无法读取模型文件{path}: {err}"))?;
+        let model: Model = serde_json::from_str(&text).map_err(|err| anyhow!("模型文件{path}格式错误: {err}"))?;
+        if model.version != MODEL_VERSION{
+            return Err(anyhow!("模型文件版本{}不兼容, 需要版本{MODEL_VERSION}, 请重新训练", model.version));
+        }
+        if model.genome.inputs != INPUTS || model.genome.outputs != OUTPUTS{
+            return Err(anyhow!("模型的输入输出数量({}, {})和NPC不一致({INPUTS}, {OUTPUTS})", model.genome.inputs, model.genome.outputs));
+        }
+        Ok(model)
+    }
+
+    /// 先写入临时文件再改名, 训练中途中断也不会留下不完整的模型
+    pub fn save(&self, path: &str) -> Result<()>{
+        let tmp = format!("{path}.tmp");
+        fs::write(&tmp, serde_json::to_string_pretty(self)?)?;
+        fs::rename(&tmp, path)?;
+        Ok(())
+    }
+
+    pub fn network(&self) -> Network{
+        Network::new(&self.genome)
+    }
+}
@@ -0,0 +1,689 @@
+//! NEAT(增强拓扑的神经进化)算法
+//!
+//! 每个基因组由节点基因和连接基因组成, 从只有输入层和输出层的最小网络开始,
+//! 通过变异逐渐增加隐藏节点和连接。连接基因带有创新号, 交叉时按创新号对齐;
+//! 种群按基因组的差异分成物种, 适应度在物种内共享, 保护刚出现的新结构不被立即淘汰。
+//!
+//! 为了推理简单, 网络只允许前馈连接, 不会产生环。
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use rand::{seq::SliceRandom, Rng};
+use serde::{Deserialize, Serialize};
+
+/// 进化参数
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NeatConfig{
+    pub population_size: usize,
+    /// 每个物种直接保留到下一代的最优个体数, 物种个体数少于5时不保留
+    pub elitism: usize,
+    /// 每个物种只有前面这个比例的个体可以繁殖
+    pub survival_threshold: f32,
+    /// 物种连续多少代没有进步就淘汰, 最好的两个物种不淘汰
+    pub max_stagnation: usize,
+    /// 基因组差异小于这个值属于同一物种, 每一代会根据物种数量自动调整
+    pub compatibility_threshold: f32,
+    /// 希望保持的物种数量
+    pub target_species: usize,
+    /// 差异公式中多余基因、不匹配基因和权重差的系数
+    pub excess_coefficient: f32,
+    pub disjoint_coefficient: f32,
+    pub weight_coefficient: f32,
+    /// 后代由交叉产生的概率, 否则只复制一个父代
+    pub crossover_rate: f32,
+    /// 变异权重的概率, 变异时每个权重有weight_replace_rate的概率重新随机, 否则加上一个扰动
+    pub weight_mutation_rate: f32,
+    pub weight_replace_rate: f32,
+    pub weight_perturbation: f32,
+    /// 增加一个连接的概率
+    pub add_connection_rate: f32,
+    /// 拆分一个连接增加一个节点的概率
+    pub add_node_rate: f32,
+    /// 重新启用一个关闭的连接的概率
+    pub enable_rate: f32,
+}
+
+impl Default for NeatConfig{
+    fn default() -> Self {
+        Self{
+            population_size: 150,
+            elitism: 1,
+            survival_threshold: 0.2,
+            max_stagnation: 15,
+            compatibility_threshold: 3.0,
+            target_species: 8,
+            excess_coefficient: 1.0,
+            disjoint_coefficient: 1.0,
+            weight_coefficient: 0.4,
+            crossover_rate: 0.75,
+            weight_mutation_rate: 0.8,
+            weight_replace_rate: 0.1,
+            weight_perturbation: 0.5,
+            add_connection_rate: 0.08,
+            add_node_rate: 0.03,
+            enable_rate: 0.01,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum NodeKind{
+    Input,
+    /// 固定输出1的偏置节点
+    Bias,
+    Hidden,
+    Output,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NodeGene{
+    pub id: usize,
+    pub kind: NodeKind,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConnectionGene{
+    pub innovation: usize,
+    pub from: usize,
+    pub to: usize,
+    pub weight: f32,
+    pub enabled: bool,
+}
+
+/// 记录出现过的结构变异, 同一代中相同的变异使用相同的创新号和节点id
+#[derive(Debug, Clone)]
+pub struct Innovations{
+    connections: HashMap<(usize, usize), usize>,
+    /// 拆分连接(创新号)时产生的节点
+    splits: HashMap<usize, usize>,
+    next_innovation: usize,
+    next_node: usize,
+}
+
+impl Innovations{
+    fn new(next_node: usize) -> Self{
+        Self{
+            connections: HashMap::new(),
+            splits: HashMap::new(),
+            next_innovation: 0,
+            next_node,
+        }
+    }
+
+    fn connection(&mut self, from: usize, to: usize) -> usize{
+        let next = &mut self.next_innovation;
+        *self.connections.entry((from, to)).or_insert_with(|| {
+            *next += 1;
+            *next - 1
+        })
+    }
+
+    fn split(&mut self, innovation: usize) -> usize{
+        let next = &mut self.next_node;
+        *self.splits.entry(innovation).or_insert_with(|| {
+            *next += 1;
+            *next - 1
+        })
+    }
+}
+
+/// 一个个体的基因
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Genome{
+    pub inputs: usize,
+    pub outputs: usize,
+    /// 节点按id排序
+    pub nodes: Vec<NodeGene>,
+    /// 连接按创新号排序
+    pub connections: Vec<ConnectionGene>,
+    #[serde(skip)]
+    pub fitness: f32,
+}
+
+impl Genome{
+    /// 最小网络: 所有输入(包括偏置)和输出之间全连接, 权重随机
+    ///
+    /// 节点id: 0..inputs是输入, inputs是偏置, 之后是输出
+    fn minimal(inputs: usize, outputs: usize, innovations: &mut Innovations, rng: &mut impl Rng) -> Self{
+        let mut nodes = vec![];
+        for id in 0..inputs{
+            nodes.push(NodeGene{ id, kind: NodeKind::Input });
+        }
+        nodes.push(NodeGene{ id: inputs, kind: NodeKind::Bias });
+        for id in inputs + 1..inputs + 1 + outputs{
+            nodes.push(NodeGene{ id, kind: NodeKind::Output });
+        }
+        let mut connections = vec![];
+        for from in 0..=inputs{
+            for to in inputs + 1..inputs + 1 + outputs{
+                connections.push(ConnectionGene{
+                    innovation: innovations.connection(from, to),
+                    from,
+                    to,
+                    weight: random_weight(rng),
+                    enabled: true,
+                });
+            }
+        }
+        connections.sort_by_key(|connection| connection.innovation);
+        Self{ inputs, outputs, nodes, connections, fitness: 0. }
+    }
+
+    fn node_kind(&self, id: usize) -> Option<NodeKind>{
+        self.nodes.iter().find(|node| node.id == id).map(|node| node.kind)
+    }
+
+    fn mutate(&mut self, config: &NeatConfig, innovations: &mut Innovations, rng: &mut impl Rng){
+        if rng.gen::<f32>() < config.weight_mutation_rate{
+            for connection in self.connections.iter_mut(){
+                if rng.gen::<f32>() < config.weight_replace_rate{
+                    connection.weight = random_weight(rng);
+                }else{
+                    connection.weight += rng.gen_range(-1.0..1.0) * config.weight_perturbation;
+                    connection.weight = connection.weight.clamp(-8., 8.);
+                }
+            }
+        }
+        if rng.gen::<f32>() < config.add_connection_rate{
+            self.add_connection(innovations, rng);
+        }
+        if rng.gen::<f32>() < config.add_node_rate{
+            self.add_node(innovations, rng);
+        }
+        if rng.gen::<f32>() < config.enable_rate{
+            if let Some(connection) = self.connections.iter_mut().filter(|connection| !connection.enabled).collect::<Vec<_>>().choose_mut(rng){
+                connection.enabled = true;
+            }
+        }
+    }
+
+    /// 在两个还没有连接的节点之间增加连接, 不会产生环
+    fn add_connection(&mut self, innovations: &mut Innovations, rng: &mut impl Rng){
+        let existing = self.connections.iter().map(|connection| (connection.from, connection.to)).collect::<HashSet<_>>();
+        let mut candidates = vec![];
+        for from in self.nodes.iter().filter(|node| node.kind != NodeKind::Output){
+            for to in self.nodes.iter().filter(|node| matches!(node.kind, NodeKind::Hidden | NodeKind::Output)){
+                if from.id != to.id && !existing.contains(&(from.id, to.id)) && !self.reachable(to.id, from.id){
+                    candidates.push((from.id, to.id));
+                }
+            }
+        }
+        if let Some(&(from, to)) = candidates.choose(rng){
+            let innovation = innovations.connection(from, to);
+            self.insert_connection(ConnectionGene{ innovation, from, to, weight: random_weight(rng), enabled: true });
+        }
+    }
+
+    /// 关闭一个连接, 用一个新节点和两个连接代替它
+    ///
+    /// 进入新节点的连接权重为1, 离开的连接沿用原来的权重, 尽量不改变网络原来的行为
+    fn add_node(&mut self, innovations: &mut Innovations, rng: &mut impl Rng){
+        let enabled = self.connections.iter().enumerate()
+            .filter(|(_, connection)| connection.enabled)
+            .map(|(idx, _)| idx)
+            .collect::<Vec<usize>>();
+        let idx = match enabled.choose(rng){
+            Some(idx) => *idx,
+            None => return
+        };
+        let connection = &self.connections[idx];
+        let (from, to, weight) = (connection.from, connection.to, connection.weight);
+        let node = innovations.split(connection.innovation);
+        // 同一个连接以前被拆分过(之后又重新启用了), 这个节点已经存在
+        if self.node_kind(node).is_some(){
+            return;
+        }
+        self.connections[idx].enabled = false;
+        self.nodes.push(NodeGene{ id: node, kind: NodeKind::Hidden });
+        self.nodes.sort_by_key(|node| node.id);
+        self.insert_connection(ConnectionGene{ innovation: innovations.connection(from, node), from, to: node, weight: 1., enabled: true });
+        self.insert_connection(ConnectionGene{ innovation: innovations.connection(node, to), from: node, to, weight, enabled: true });
+    }
+
+    fn insert_connection(&mut self, connection: ConnectionGene){
+        let idx = self.connections.partition_point(|other| other.innovation < connection.innovation);
+        self.connections.insert(idx, connection);
+    }
+
+    /// 是否存在从from到to的路径(不管连接是否启用)
+    fn reachable(&self, from: usize, to: usize) -> bool{
+        let mut stack = vec![from];
+        let mut visited = HashSet::new();
+        while let Some(node) = stack.pop(){
+            if node == to{
+                return true;
+            }
+            if visited.insert(node){
+                stack.extend(self.connections.iter().filter(|connection| connection.from == node).map(|connection| connection.to));
+            }
+        }
+        false
+    }
+
+    /// 交叉, self是适应度更高的父代
+    ///
+    /// 两个父代都有的连接随机继承一个, 只有self有的连接全部继承; 任意一个父代中关闭的连接有75%的概率保持关闭
+    fn crossover(&self, other: &Genome, rng: &mut impl Rng) -> Genome{
+        let other_connections = other.connections.iter().map(|connection| (connection.innovation, connection)).collect::<HashMap<_, _>>();
+        let mut child = self.clone();
+        for connection in child.connections.iter_mut(){
+            if let Some(other) = other_connections.get(&connection.innovation){
+                if rng.gen::<bool>(){
+                    connection.weight = other.weight;
+                }
+                if !connection.enabled || !other.enabled{
+                    connection.enabled = rng.gen::<f32>() >= 0.75;
+                }
+            }
+        }
+        child.fitness = 0.;
+        child
+    }
+
+    /// 两个基因组的差异
+    fn distance(&self, other: &Genome, config: &NeatConfig) -> f32{
+        let a = self.connections.iter().map(|connection| (connection.innovation, connection.weight)).collect::<BTreeMap<_, _>>();
+        let b = other.connections.iter().map(|connection| (connection.innovation, connection.weight)).collect::<BTreeMap<_, _>>();
+        let max_a = a.keys().next_back().copied().unwrap_or(0);
+        let max_b = b.keys().next_back().copied().unwrap_or(0);
+
+        let mut matching = 0;
+        let mut weight_difference = 0.;
+        let mut disjoint = 0;
+        let mut excess = 0;
+        for (innovation, weight) in a.iter(){
+            match b.get(innovation){
+                Some(other_weight) => {
+                    matching += 1;
+                    weight_difference += (weight - other_weight).abs();
+                }
+                None if *innovation > max_b => excess += 1,
+                None => disjoint += 1,
+            }
+        }
+        for innovation in b.keys().filter(|innovation| !a.contains_key(innovation)){
+            if *innovation > max_a{
+                excess += 1;
+            }else{
+                disjoint += 1;
+            }
+        }
+        let n = a.len().max(b.len()).max(1) as f32;
+        let average_weight = if matching > 0 { weight_difference / matching as f32 } else { 0. };
+        config.excess_coefficient * excess as f32 / n
+            + config.disjoint_coefficient * disjoint as f32 / n
+            + config.weight_coefficient * average_weight
+    }
+}
+
+fn random_weight(rng: &mut impl Rng) -> f32{
+    rng.gen_range(-2.0..2.0)
+}
+
+/// 由基因组生成的前馈网络
+#[derive(Debug, Clone)]
+pub struct Network{
+    inputs: usize,
+    outputs: Vec<usize>,
+    /// 按拓扑顺序排列的隐藏节点和输出节点, 以及它们的输入连接(来源节点, 权重)
+    order: Vec<(usize, Vec<(usize, f32)>)>,
+    /// 每个节点的输出值, 按节点id索引
+    values: Vec<f32>,
+}
+
+impl Network{
+    pub fn new(genome: &Genome) -> Self{
+        let max_id = genome.nodes.iter().map(|node| node.id).max().unwrap_or(0);
+        let mut incoming: BTreeMap<usize, Vec<(usize, f32)>> = BTreeMap::new();
+        for connection in genome.connections.iter().filter(|connection| connection.enabled){
+            incoming.entry(connection.to).or_default().push((connection.from, connection.weight));
+        }
+
+        // 拓扑排序: 输入和偏置已经有值, 依次计算所有输入都已经计算好的节点
+        let mut ready = genome.nodes.iter()
+            .filter(|node| matches!(node.kind, NodeKind::Input | NodeKind::Bias))
+            .map(|node| node.id)
+            .collect::<HashSet<usize>>();
+        let mut pending = genome.nodes.iter()
+            .filter(|node| matches!(node.kind, NodeKind::Hidden | NodeKind::Output))
+            .map(|node| node.id)
+            .collect::<Vec<usize>>();
+        let mut order = vec![];
+        while !pending.is_empty(){
+            let (now, later): (Vec<usize>, Vec<usize>) = pending.iter().partition(|id| {
+                incoming.get(id).into_iter().flatten().all(|(from, _)| ready.contains(from))
+            });
+            // 只允许前馈连接, 不会出现环; 万一出现, 剩下的节点输出0
+            if now.is_empty(){
+                break;
+            }
+            for id in now{
+                ready.insert(id);
+                order.push((id, incoming.remove(&id).unwrap_or_default()));
+            }
+            pending = later;
+        }
+
+        let outputs = genome.nodes.iter().filter(|node| node.kind == NodeKind::Output).map(|node| node.id).collect();
+        Self{
+            inputs: genome.inputs,
+            outputs,
+            order,
+            values: vec![0.; max_id + 1],
+        }
+    }
+
+    /// 计算网络输出, 输出范围是(0, 1)
+    pub fn activate(&mut self, inputs: &[f32]) -> Vec<f32>{
+        self.values.iter_mut().for_each(|value| *value = 0.);
+        for (idx, value) in inputs.iter().take(self.inputs).enumerate(){
+            self.values[idx] = *value;
+        }
+        self.values[self.inputs] = 1.;
+        for (id, links) in self.order.iter(){
+            let sum = links.iter().map(|(from, weight)| self.values[*from] * weight).sum::<f32>();
+            self.values[*id] = sigmoid(sum);
+        }
+        self.outputs.iter().map(|id| self.values[*id]).collect()
+    }
+}
+
+fn sigmoid(x: f32) -> f32{
+    1. / (1. + (-4.9 * x).exp())
+}
+
+struct Species{
+    /// 用来判断新个体是否属于这个物种
+    representative: Genome,
+    members: Vec<Genome>,
+    best_fitness: f32,
+    /// 上一次进步的代数
+    last_improved: usize,
+}
+
+/// 种群
+pub struct Population{
+    pub config: NeatConfig,
+    pub genomes: Vec<Genome>,
+    pub generation: usize,
+    innovations: Innovations,
+    species: Vec<Species>,
+}
+
+impl Population{
+    pub fn new(config: NeatConfig, inputs: usize, outputs: usize, rng: &mut impl Rng) -> Self{
+        let mut innovations = Innovations::new(inputs + 1 + outputs);
+        let genomes = (0..config.population_size)
+            .map(|_| Genome::minimal(inputs, outputs, &mut innovations, rng))
+            .collect();
+        Self{
+            config,
+            genomes,
+            generation: 0,
+            innovations,
+            species: vec![],
+        }
+    }
+
+    /// 物种数量
+    pub fn species_count(&self) -> usize{
+        self.species.len()
+    }
+
+    /// 所有个体的fitness都已经计算好以后, 产生下一代
+    pub fn evolve(&mut self, rng: &mut impl Rng){
+        self.speciate();
+        self.remove_stagnant();
+
+        // 共享适应度: 个体适应度除以物种大小, 物种的后代数量和平均共享适应度成正比
+        let min_fitness = self.species.iter().flat_map(|species| species.members.iter()).map(|genome| genome.fitness).fold(f32::MAX, f32::min);
+        let adjusted = self.species.iter()
+            .map(|species| species.members.iter().map(|genome| genome.fitness - min_fitness).sum::<f32>() / species.members.len() as f32)
+            .collect::<Vec<f32>>();
+        let counts = offspring_counts(&adjusted, self.config.population_size);
+
+        // 新的一代中相同的结构变异使用相同的创新号
+        self.innovations.connections.clear();
+        self.innovations.splits.clear();
+
+        let mut next = vec![];
+        for (species, count) in self.species.iter_mut().zip(counts){
+            if count == 0{
+                continue;
+            }
+            species.members.sort_by(|a, b| b.fitness.total_cmp(&a.fitness));
+            let mut count = count;
+            if species.members.len() >= 5{
+                for genome in species.members.iter().take(self.config.elitism.min(count)){
+                    next.push(genome.clone());
+                    count -= 1;
+                }
+            }
+            let parents = ((species.members.len() as f32 * self.config.survival_threshold).ceil() as usize).max(1);
+            let parents = &species.members[..parents];
+            for _ in 0..count{
+                let mother = parents.choose(rng).unwrap();
+                let mut child = if parents.len() > 1 && rng.gen::<f32>() < self.config.crossover_rate{
+                    let father = parents.choose(rng).unwrap();
+                    if mother.fitness >= father.fitness{
+                        mother.crossover(father, rng)
+                    }else{
+                        father.crossover(mother, rng)
+                    }
+                }else{
+                    mother.clone()
+                };
+                child.mutate(&self.config, &mut self.innovations, rng);
+                next.push(child);
+            }
+        }
+
+        for species in self.species.iter_mut(){
+            species.members.clear();
+        }
+        self.genomes = next;
+        self.generation += 1;
+    }
+
+    /// 把所有个体分到物种中, 没有合适的物种时创建新物种
+    fn speciate(&mut self){
+        for species in self.species.iter_mut(){
+            species.members.clear();
+        }
+        for genome in self.genomes.drain(..){
+            match self.species.iter_mut().find(|species| species.representative.distance(&genome, &self.config) < self.config.compatibility_threshold){
+                Some(species) => species.members.push(genome),
+                None => self.species.push(Species{
+                    representative: genome.clone(),
+                    members: vec![genome],
+                    best_fitness: f32::MIN,
+                    last_improved: self.generation,
+                }),
+            }
+        }
+        self.species.retain(|species| !species.members.is_empty());
+        for species in self.species.iter_mut(){
+            let best = species.members.iter().max_by(|a, b| a.fitness.total_cmp(&b.fitness)).unwrap();
+            if best.fitness > species.best_fitness{
+                species.best_fitness = best.fitness;
+                species.last_improved = self.generation;
+            }
+            // 下一代用这一代的最优个体作为代表
+            species.representative = best.clone();
+        }
+        // 物种太少时降低阈值, 太多时提高阈值
+        if self.species.len() < self.config.target_species{
+            self.config.compatibility_threshold = (self.config.compatibility_threshold - 0.1).max(0.3);
+        }else if self.species.len() > self.config.target_species{
+            self.config.compatibility_threshold += 0.1;
+        }
+    }
+
+    /// 淘汰长期没有进步的物种, 至少保留最好的两个
+    fn remove_stagnant(&mut self){
+        self.species.sort_by(|a, b| b.best_fitness.total_cmp(&a.best_fitness));
+        let generation = self.generation;
+        let max_stagnation = self.config.max_stagnation;
+        let mut idx = 0;
+        self.species.retain(|species| {
+            idx += 1;
+            idx <= 2 || generation - species.last_improved <= max_stagnation
+        });
+    }
+}
+
+/// 按每个物种的平均共享适应度分配下一代的个体数量, 总数等于种群大小
+///
+/// 四舍五入以后总数可能不等于种群大小: 不够时差额给最好的物种, 多出来的从最差的物种开始扣除
+fn offspring_counts(adjusted: &[f32], size: usize) -> Vec<usize>{
+    let total = adjusted.iter().sum::<f32>();
+    let mut counts = adjusted.iter()
+        .map(|fitness| if total > 0. { (fitness / total * size as f32).round() as usize } else { size / adjusted.len() })
+        .collect::<Vec<usize>>();
+    // 物种按适应度从差到好排列
+    let mut order = (0..adjusted.len()).collect::<Vec<usize>>();
+    order.sort_by(|a, b| adjusted[*a].total_cmp(&adjusted[*b]));
+    let sum = counts.iter().sum::<usize>();
+    if sum < size{
+        if let Some(best) = order.last(){
+            counts[*best] += size - sum;
+        }
+    }else{
+        let mut extra = sum - size;
+        for idx in order{
+            let removed = extra.min(counts[idx]);
+            counts[idx] -= removed;
+            extra -= removed;
+        }
+    }
+    counts
+}
+
+#[cfg(test)]
+mod tests{
+    use rand::SeedableRng;
+    use rand_chacha::ChaCha8Rng;
+
+    use super::*;
+
+    fn node(id: usize, kind: NodeKind) -> NodeGene{
+        NodeGene{ id, kind }
+    }
+
+    fn connection(innovation: usize, from: usize, to: usize, weight: f32, enabled: bool) -> ConnectionGene{
+        ConnectionGene{ innovation, from, to, weight, enabled }
+    }
+
+    fn genome(inputs: usize, outputs: usize, nodes: Vec<NodeGene>, connections: Vec<ConnectionGene>) -> Genome{
+        Genome{ inputs, outputs, nodes, connections, fitness: 0. }
+    }
+
+    #[test]
+    fn network_orders_hidden_nodes_topologically(){
+        // 0(输入) -> 4 -> 3 -> 2(输出), 隐藏节点的id顺序和计算顺序相反
+        let genome = genome(1, 1, vec![
+            node(0, NodeKind::Input),
+            node(1, NodeKind::Bias),
+            node(2, NodeKind::Output),
+            node(3, NodeKind::Hidden),
+            node(4, NodeKind::Hidden),
+        ], vec![
+            connection(0, 0, 4, 1., true),
+            connection(1, 4, 3, 1., true),
+            connection(2, 3, 2, 1., true),
+        ]);
+        let mut network = Network::new(&genome);
+        let order = network.order.iter().map(|(id, _)| *id).collect::<Vec<usize>>();
+        assert_eq!(order, vec![4, 3, 2]);
+        let output = network.activate(&[0.5]);
+        assert_eq!(output, vec![sigmoid(sigmoid(sigmoid(0.5)))]);
+    }
+
+    #[test]
+    fn network_ignores_disabled_connections(){
+        let genome = genome(1, 1, vec![
+            node(0, NodeKind::Input),
+            node(1, NodeKind::Bias),
+            node(2, NodeKind::Output),
+        ], vec![
+            connection(0, 0, 2, 1., true),
+            connection(1, 1, 2, 5., false),
+        ]);
+        let mut network = Network::new(&genome);
+        assert_eq!(network.order, vec![(2, vec![(0, 1.)])]);
+        assert_eq!(network.activate(&[0.5]), vec![sigmoid(0.5)]);
+    }
+
+    #[test]
+    fn add_node_splits_a_connection(){
+        let mut rng = ChaCha8Rng::seed_from_u64(1);
+        let mut innovations = Innovations::new(2);
+        let mut genome = genome(1, 0, vec![
+            node(0, NodeKind::Input),
+            node(1, NodeKind::Output),
+        ], vec![
+            connection(innovations.connection(0, 1), 0, 1, 0.7, true),
+        ]);
+        let mut other = genome.clone();
+
+        genome.add_node(&mut innovations, &mut rng);
+        assert_eq!(genome.node_kind(2), Some(NodeKind::Hidden));
+        assert_eq!(genome.connections.len(), 3);
+        assert!(!genome.connections[0].enabled);
+        let links = genome.connections[1..].iter().map(|c| (c.from, c.to, c.weight, c.enabled)).collect::<Vec<_>>();
+        assert_eq!(links, vec![(0, 2, 1., true), (2, 1, 0.7, true)]);
+
+        // 同一代中拆分同一个连接得到相同的节点id和创新号
+        other.add_node(&mut innovations, &mut rng);
+        let innovations_of = |genome: &Genome| genome.connections.iter().map(|c| c.innovation).collect::<Vec<_>>();
+        assert_eq!(innovations_of(&other), innovations_of(&genome));
+        assert_eq!(other.node_kind(2), Some(NodeKind::Hidden));
+    }
+
+    #[test]
+    fn crossover_keeps_the_fitter_parents_structure(){
+        let mut rng = ChaCha8Rng::seed_from_u64(2);
+        let nodes = vec![
+            node(0, NodeKind::Input),
+            node(1, NodeKind::Bias),
+            node(2, NodeKind::Output),
+            node(3, NodeKind::Hidden),
+        ];
+        let mut fitter = genome(1, 1, nodes.clone(), vec![
+            connection(0, 0, 2, 1., true),
+            connection(1, 1, 2, 1., true),
+            connection(2, 0, 3, 1., true),
+            connection(3, 3, 2, 1., true),
+        ]);
+        fitter.fitness = 10.;
+        let other = genome(1, 1, nodes[..3].to_vec(), vec![
+            connection(0, 0, 2, -1., true),
+            connection(1, 1, 2, -1., true),
+            connection(4, 1, 2, -1., true),
+        ]);
+
+        for _ in 0..20{
+            let child = fitter.crossover(&other, &mut rng);
+            let innovations = child.connections.iter().map(|c| c.innovation).collect::<Vec<_>>();
+            assert_eq!(innovations, vec![0, 1, 2, 3]);
+            assert_eq!(child.nodes.len(), 4);
+            assert_eq!(child.fitness, 0.);
+            // 两个父代都有的连接继承其中一个的权重, 只有fitter有的连接保持不变
+            assert!(child.connections[..2].iter().all(|c| c.weight == 1. || c.weight == -1.));
+            assert!(child.connections[2..].iter().all(|c| c.weight == 1.));
+            assert!(child.connections.iter().all(|c| c.enabled));
+        }
+    }
+
+    #[test]
+    fn offspring_rounding_excess_comes_from_the_worst_species(){
+        // 2, 0.5, 1.5 四舍五入以后是2, 1, 2, 比种群大小多1
+        assert_eq!(offspring_counts(&[4., 1., 3.], 4), vec![2, 0, 2]);
+    }
+
+    #[test]
+    fn offspring_rounding_shortfall_goes_to_the_best_species(){
+        assert_eq!(offspring_counts(&[2., 2., 3.], 8), vec![2, 2, 4]);
+        assert_eq!(offspring_counts(&[0., 0.], 5), vec![2, 3]);
+    }
+}
@@ -0,0 +1,41 @@
+//! NPC的感知
+//!
+//! 输入是snake中定义的观察特征向量([`snake::observe`]), 和外部工具使用的完全一样。
+//! 4个输出分别对应上、下、左、右, 取输出最大的方向。
+
+use snake::{Direction, SnakeBrain, WorldView, OBSERVATION_SIZE};
+
+use crate::neat::Network;
+
+/// 输入神经元数量
+pub const INPUTS: usize = OBSERVATION_SIZE;
+/// 输出神经元数量
+pub const OUTPUTS: usize = 4;
+/// 输出神经元对应的方向
+pub const OUTPUT_DIRECTIONS: [snake::Direction; OUTPUTS] = [Direction::Up, Direction::Down, Direction::Left, Direction::Right];
+
+/// 用网络决定玩家下一步的方向
+pub fn think(network: &mut Network, view: &WorldView, player_id: &str) -> Option<snake::Direction>{
+    let inputs = view.observe(player_id)?;
+    let outputs = network.activate(&inputs);
+    outputs.iter().enumerate()
+        .max_by(|a, b| a.1.total_cmp(b.1))
+        .map(|(idx, _)| OUTPUT_DIRECTIONS[idx])
+}
+
+/// 用NEAT网络控制的大脑
+pub struct NeatBrain{
+    network: Network,
+}
+
+impl NeatBrain{
+    pub fn new(network: Network) -> Self{
+        Self{ network }
+    }
+}
+
+impl SnakeBrain for NeatBrain{
+    fn think(&mut self, view: &WorldView, player_id: &str) -> Option<snake::Direction>{
+        think(&mut self.network, view, player_id)
+    }
+}
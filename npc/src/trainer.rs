@@ -0,0 +1,164 @@
+//! 训练: 让种群中的个体在模拟的游戏中比赛, 按比赛结果计算适应度
+//!
+//! 每一轮把所有个体随机分组, 每组在一个独立的场地里用真实的游戏规则对战,
+//! 一个个体参加多轮比赛, 适应度取平均值。不同的比赛在多个线程中同时进行。
+
+use std::{collections::HashMap, thread};
+
+use rand::{seq::SliceRandom, Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use snake::{GameConfig, GameEvent, GameRng, GameState, WorldView};
+
+use crate::{neat::{Genome, Network, Population}, sensor::think};
+
+/// 每存活一个移动周期的得分
+pub const SURVIVAL_SCORE: f32 = 1.;
+/// 每长大一节的得分
+pub const LENGTH_SCORE: f32 = 50.;
+/// 每撞死一条蛇的得分
+pub const KILL_SCORE: f32 = 100.;
+
+#[derive(Debug, Clone)]
+pub struct TrainConfig{
+    pub game: GameConfig,
+    /// 每场比赛的NPC数量
+    pub group_size: usize,
+    /// 每个个体参加的比赛场数
+    pub rounds: usize,
+    /// 每场比赛最多进行的移动周期
+    pub max_ticks: u64,
+    /// 连续这么多个移动周期没有吃到食物的蛇饿死, 避免一直绕圈
+    pub hunger_ticks: u64,
+    pub threads: usize,
+}
+
+impl Default for TrainConfig{
+    fn default() -> Self {
+        Self{
+            game: GameConfig::default(),
+            group_size: 8,
+            rounds: 3,
+            max_ticks: 2000,
+            hunger_ticks: 200,
+            threads: thread::available_parallelism().map(|n| n.get()).unwrap_or(1),
+        }
+    }
+}
+
+/// 一条蛇在一场比赛中的成绩
+#[derive(Debug, Clone, Default)]
+pub struct Score{
+    pub ticks: u64,
+    pub max_length: usize,
+    pub kills: u32,
+}
+
+impl Score{
+    pub fn fitness(&self, start_length: usize) -> f32{
+        self.ticks as f32 * SURVIVAL_SCORE
+            + self.max_length.saturating_sub(start_length) as f32 * LENGTH_SCORE
+            + self.kills as f32 * KILL_SCORE
+    }
+}
+
+/// 进行一场比赛, 返回每个参赛者的成绩
+pub fn play(genomes: &[&Genome], config: &TrainConfig, seed: u64) -> Vec<Score>{
+    let mut world = GameState::new(config.game.clone(), GameRng::new(seed));
+    let mut players = vec![];
+    // 玩家id对应的参赛者序号
+    let mut index = HashMap::new();
+    for (idx, genome) in genomes.iter().enumerate(){
+        let player_id = format!("npc-{idx}");
+        if world.add_player(player_id.clone(), player_id.clone()).is_ok(){
+            players.push((player_id.clone(), Network::new(genome)));
+            index.insert(player_id, idx);
+        }
+    }
+    // 开局先放满食物
+    while world.spawn_food().is_some(){}
+
+    let mut scores = vec![Score::default(); genomes.len()];
+    let mut last_eaten = HashMap::new();
+
+    while world.tick < config.max_ticks && !world.snakes.is_empty(){
+        let view = WorldView::new(&world);
+        let mut inputs = vec![];
+        for (player_id, network) in players.iter_mut(){
+            if let Some(direction) = think(network, &view, player_id){
+                inputs.push((player_id.clone(), direction));
+            }
+        }
+
+        for event in world.step(&inputs){
+            match event{
+                GameEvent::FoodEaten { player_id, .. } => {
+                    last_eaten.insert(player_id, world.tick);
+                }
+                GameEvent::PlayerDied(death) => {
+                    if let Some(killer) = death.killer.as_ref().and_then(|killer| index.get(killer)){
+                        scores[*killer].kills = death.killer_kills.max(scores[*killer].kills);
+                    }
+                }
+                _ => ()
+            }
+        }
+
+        let hungry = world.snakes.keys()
+            .filter(|player_id| world.tick - last_eaten.get(*player_id).copied().unwrap_or(0) > config.hunger_ticks)
+            .cloned()
+            .collect::<Vec<String>>();
+        for player_id in hungry{
+            world.remove_player(&player_id);
+        }
+
+        for snake in world.snakes.values(){
+            let score = &mut scores[index[&snake.player_id]];
+            score.ticks = world.tick;
+            score.max_length = score.max_length.max(snake.body.len());
+        }
+    }
+    scores
+}
+
+/// 计算种群中所有个体的适应度
+pub fn evaluate(population: &mut Population, config: &TrainConfig, seed: u64){
+    let mut rng = ChaCha8Rng::seed_from_u64(seed);
+    let count = population.genomes.len();
+
+    // 每一轮把个体打乱后分组, 每组一场比赛
+    let mut games = vec![];
+    for _ in 0..config.rounds{
+        let mut order = (0..count).collect::<Vec<usize>>();
+        order.shuffle(&mut rng);
+        for group in order.chunks(config.group_size.max(1)){
+            games.push((group.to_vec(), rng.gen::<u64>()));
+        }
+    }
+
+    let genomes = &population.genomes;
+    let threads = config.threads.max(1);
+    let chunk_size = (games.len() + threads - 1) / threads;
+    let results = thread::scope(|scope| {
+        let handles = games.chunks(chunk_size.max(1)).map(|chunk| {
+            scope.spawn(move || {
+                chunk.iter().map(|(group, seed)| {
+                    let members = group.iter().map(|idx| &genomes[*idx]).collect::<Vec<&Genome>>();
+                    (group, play(&members, config, *seed))
+                }).collect::<Vec<_>>()
+            })
+        }).collect::<Vec<_>>();
+        handles.into_iter().flat_map(|handle| handle.join().unwrap()).collect::<Vec<_>>()
+    });
+
+    let mut totals = vec![0.; count];
+    let mut games_played = vec![0; count];
+    for (group, scores) in results{
+        for (idx, score) in group.iter().zip(scores){
+            totals[*idx] += score.fitness(config.game.start_length);
+            games_played[*idx] += 1;
+        }
+    }
+    for (idx, genome) in population.genomes.iter_mut().enumerate(){
+        genome.fitness = totals[idx] / games_played[idx].max(1) as f32;
+    }
+}
@@ -0,0 +1,151 @@
+use std::{env, fmt::Debug, fs, path::Path, str::FromStr};
+use anyhow::{anyhow, Result};
+use bevy::prelude::Resource;
+use snake::GameConfig;
+
+use crate::limit::ConnectionLimits;
+
+/// 服务器命令行参数
+///
+/// server [监听地址] [--name 服务器名称] [--seed 随机数种子] [--record 录像文件] [--config 设置文件.toml] [设置参数]
+///
+/// server --replay 录像文件 [--until 移动周期]
+///
+/// 设置参数会覆盖设置文件中的值: --width --height --tick --food-interval --max-foods --start-length --max-players
+///
+/// 限流参数: --rate 每秒消息数 --burst 连续消息数 --max-message-size 单条消息字节数
+///
+/// 观众: --max-spectators 最多观众数量
+///
+/// 断线重连: --grace 断线后保留玩家的秒数
+///
+/// NPC: --npcs 默认房间中的NPC数量, 0表示不要NPC --npc-model 训练程序生成的模型文件
+/// --npc-brains 逗号分隔的NPC大脑, 依次分配给每个NPC: neat, random, greedy, floodfill, astar;
+/// 默认在模型文件存在时使用neat, 否则使用floodfill
+#[derive(Resource, Debug, Clone)]
+pub struct ServerArgs{
+    pub addr: String,
+    /// 服务器名称, 显示在网页标题上
+    pub name: String,
+    /// 不指定时启动服务器时随机选择
+    pub seed: Option<u64>,
+    /// 把这局游戏录制到文件
+    pub record: Option<String>,
+    /// 不启动服务器, 重放录像文件
+    pub replay: Option<String>,
+    /// 重放到第几个移动周期, 默认重放到最后一条消息
+    pub until: Option<u64>,
+    pub config: GameConfig,
+    /// 每个连接的限流设置, 超过限制的客户端会被断开
+    pub limits: ConnectionLimits,
+    /// 最多同时观看的观众数量, 和玩家数量分开限制
+    pub max_spectators: usize,
+    /// 断线后保留玩家的秒数, 期间可以用会话令牌重连
+    pub grace_period: f32,
+    /// 默认房间中的NPC数量, 为0时不启动NPC
+    pub npcs: usize,
+    pub npc_model: String,
+    pub npc_brains: Vec<String>,
+}
+
+impl ServerArgs{
+    pub fn parse() -> Result<Self>{
+        let mut addr = None;
+        let mut name = None;
+        let mut seed = None;
+        let mut record = None;
+        let mut replay = None;
+        let mut until = None;
+        let mut config_file = None;
+        let mut overrides = vec![];
+        let mut limits = ConnectionLimits::default();
+        let mut max_spectators = 16;
+        let mut grace_period = 30.;
+        let mut npcs = 4;
+        let mut npc_model = "npc_model.json".to_string();
+        let mut npc_brains = None;
+
+        let mut args = env::args().skip(1);
+        while let Some(arg) = args.next(){
+            let mut value = || args.next().ok_or(anyhow!("{arg}缺少参数"));
+            match arg.as_str(){
+                "--name" => name = Some(value()?),
+                "--seed" => seed = Some(parse_value(&arg, &value()?)?),
+                "--record" => record = Some(value()?),
+                "--replay" => replay = Some(value()?),
+                "--until" => until = Some(parse_value(&arg, &value()?)?),
+                "--config" => config_file = Some(value()?),
+                "--rate" => limits.messages_per_second = parse_value(&arg, &value()?)?,
+                "--burst" => limits.burst = parse_value(&arg, &value()?)?,
+                "--max-message-size" => limits.max_message_size = parse_value(&arg, &value()?)?,
+                "--max-spectators" => max_spectators = parse_value(&arg, &value()?)?,
+                "--grace" => grace_period = parse_value(&arg, &value()?)?,
+                "--npcs" => npcs = parse_value(&arg, &value()?)?,
+                "--npc-model" => npc_model = value()?,
+                "--npc-brains" => npc_brains = Some(value()?.split(',').map(|name| name.trim().to_string()).filter(|name| !name.is_empty()).collect::<Vec<String>>()),
+                "--width" | "--height" | "--tick" | "--food-interval"
+                | "--max-foods" | "--start-length" | "--max-players" => {
+                    let value = value()?;
+                    overrides.push((arg, value));
+                }
+                _ if arg.starts_with("--") => return Err(anyhow!("未知参数: {arg}")),
+                _ => addr = Some(arg),
+            }
+        }
+
+        let mut config = match config_file{
+            Some(path) => {
+                let text = fs::read_to_string(&path).map_err(|err| anyhow!("无法读取设置文件 {path}: {:?}", err))?;
+                toml::from_str(&text).map_err(|err| anyhow!("设置文件格式错误 {path}: {err}"))?
+            }
+            None => GameConfig::default()
+        };
+        for (flag, value) in overrides{
+            match flag.as_str(){
+                "--width" => config.arena_width = parse_value(&flag, &value)?,
+                "--height" => config.arena_height = parse_value(&flag, &value)?,
+                "--tick" => config.tick_interval = parse_value(&flag, &value)?,
+                "--food-interval" => config.food_spawn_interval = parse_value(&flag, &value)?,
+                "--max-foods" => config.max_foods = parse_value(&flag, &value)?,
+                "--start-length" => config.start_length = parse_value(&flag, &value)?,
+                "--max-players" => config.max_players = parse_value(&flag, &value)?,
+                _ => ()
+            }
+        }
+        config.validate()?;
+        if limits.messages_per_second <= 0. || limits.burst < 1. || limits.max_message_size == 0{
+            return Err(anyhow!("限流参数错误: {:?}", limits));
+        }
+        // 没有训练过模型时也能直接启动, 改用内置的大脑
+        let npc_brains = npc_brains.unwrap_or_else(|| match Path::new(&npc_model).exists(){
+            true => vec!["neat".to_string()],
+            false => vec!["floodfill".to_string()],
+        });
+        if npc_brains.is_empty(){
+            return Err(anyhow!("--npc-brains参数错误"));
+        }
+        if grace_period.is_nan() || grace_period < 0.{
+            return Err(anyhow!("--grace参数错误: {grace_period}"));
+        }
+
+        Ok(Self{
+            addr: addr.unwrap_or_else(|| "127.0.0.1:8080".to_string()),
+            name: name.unwrap_or_else(|| "多人在线贪吃蛇".to_string()),
+            seed,
+            record,
+            replay,
+            until,
+            config,
+            limits,
+            max_spectators,
+            grace_period,
+            npcs,
+            npc_model,
+            npc_brains,
+        })
+    }
+}
+
+fn parse_value<T: FromStr>(name: &str, value: &str) -> Result<T> where T::Err: Debug{
+    value.parse().map_err(|err| anyhow!("{name}参数错误 {value}: {:?}", err))
+}
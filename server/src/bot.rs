@@ -0,0 +1,91 @@
+//! 服务器上的NPC
+//!
+//! NPC在默认房间中游戏, 保证没有玩家时场地也不是空的。
+//! 每个NPC的大脑可以分别设置: 训练程序生成的模型(neat), 或者snake中内置的策略。
+//! NPC的加入和转向和玩家消息一样经过录像, 重放时结果一致。
+
+use anyhow::{anyhow, Result};
+use bevy::prelude::*;
+use npc::{Model, NeatBrain};
+use snake::{builtin_brain, MessageFromClient, SnakeBrain, WorldView};
+
+use crate::{apply_client_message, record_message, replay::Recorder, room::{Rooms, DEFAULT_ROOM}};
+
+/// NPC死亡后等待多少个移动周期重新加入
+pub const NPC_RESPAWN_TICKS: u64 = 20;
+
+struct Bot{
+    player_id: String,
+    name: String,
+    brain: Box<dyn SnakeBrain>,
+    /// 不在游戏中时, 到这个移动周期重新加入(加入失败时也等待同样的时间再试)
+    respawn_at: u64,
+}
+
+#[derive(Resource)]
+pub struct Bots{
+    bots: Vec<Bot>,
+}
+
+impl Bots{
+    /// 第i个NPC使用brains[i % brains.len()]作为大脑, 用到neat时才加载模型文件
+    pub fn new(count: usize, brains: &[String], model_path: &str, seed: u64) -> Result<Self>{
+        let model = match brains.iter().any(|name| name == "neat"){
+            true => {
+                let model = Model::load(model_path)?;
+                info!("加载NPC模型 {model_path}: 第{}代 适应度:{}", model.generation, model.fitness);
+                Some(model)
+            }
+            false => None
+        };
+        let mut bots = vec![];
+        for idx in 1..=count{
+            let name = &brains[(idx - 1) % brains.len()];
+            let brain: Box<dyn SnakeBrain> = match (name.as_str(), model.as_ref()){
+                ("neat", Some(model)) => Box::new(NeatBrain::new(model.network())),
+                _ => builtin_brain(name, seed.wrapping_add(idx as u64)).ok_or(anyhow!("未知的NPC大脑: {name}"))?,
+            };
+            bots.push(Bot{
+                player_id: format!("npc-{idx}"),
+                name: format!("NPC{idx}"),
+                brain,
+                respawn_at: 0,
+            });
+        }
+        Ok(Self{ bots })
+    }
+}
+
+/// 每个移动周期开始前, 让NPC加入游戏或者根据感知决定方向
+pub fn drive_bots(bots: Option<ResMut<Bots>>, mut rooms: ResMut<Rooms>, mut recorder: Option<ResMut<Recorder>>){
+    let mut bots = match bots{
+        Some(v) => v,
+        None => return
+    };
+    let room = match rooms.rooms.get_mut(&DEFAULT_ROOM){
+        Some(v) => v,
+        None => return
+    };
+    // 先让所有NPC根据同一个场地做决定, 再把决定作用到游戏中
+    let view = WorldView::new(&room.world);
+    let mut messages = vec![];
+    for bot in bots.bots.iter_mut(){
+        let msg = if room.world.snakes.contains_key(&bot.player_id){
+            bot.respawn_at = room.world.tick + NPC_RESPAWN_TICKS;
+            match bot.brain.think(&view, &bot.player_id){
+                Some(direction) => MessageFromClient::Turn(direction),
+                None => continue
+            }
+        }else if room.world.tick >= bot.respawn_at{
+            bot.respawn_at = room.world.tick + NPC_RESPAWN_TICKS;
+            MessageFromClient::JoinGame(bot.name.clone())
+        }else{
+            continue;
+        };
+        messages.push((bot.player_id.clone(), msg));
+    }
+    for (player_id, msg) in messages{
+        record_message(recorder.as_deref_mut(), room, &player_id, &msg);
+        apply_client_message(&mut room.world, &mut room.inputs, player_id, msg);
+    }
+}
@@ -0,0 +1,91 @@
+//! 连接限流
+//!
+//! 每个连接有一个令牌桶, 每条消息消耗一个令牌, 令牌按固定速度恢复。
+//! 令牌用完说明客户端发送得太快, 服务器断开这个连接, 避免一个客户端拖慢整个游戏循环。
+
+use std::time::Instant;
+
+use snake::{Direction, MessageFromClient};
+
+/// 每个连接的限制
+#[derive(Debug, Clone)]
+pub struct ConnectionLimits{
+    /// 每秒恢复的消息数
+    pub messages_per_second: f32,
+    /// 最多可以连续发送的消息数
+    pub burst: f32,
+    /// 单条消息最大字节数
+    pub max_message_size: usize,
+}
+
+impl Default for ConnectionLimits{
+    fn default() -> Self {
+        Self {
+            messages_per_second: 20.,
+            burst: 40.,
+            max_message_size: 1024,
+        }
+    }
+}
+
+/// 令牌桶
+pub struct RateLimiter{
+    rate: f32,
+    capacity: f32,
+    tokens: f32,
+    last: Instant,
+}
+
+impl RateLimiter{
+    pub fn new(limits: &ConnectionLimits) -> Self{
+        Self{
+            rate: limits.messages_per_second,
+            capacity: limits.burst,
+            tokens: limits.burst,
+            last: Instant::now(),
+        }
+    }
+
+    /// 消耗一个令牌, 令牌用完时返回false
+    pub fn try_acquire(&mut self) -> bool{
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last).as_secs_f32();
+        self.last = now;
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        if self.tokens >= 1.{
+            self.tokens -= 1.;
+            true
+        }else{
+            false
+        }
+    }
+}
+
+/// 合并重复的转向: 一个移动周期内重复发送同一个方向只转发第一次
+pub struct TurnFilter{
+    interval: f32,
+    last: Option<(Direction, Instant)>,
+}
+
+impl TurnFilter{
+    /// interval: 移动周期(秒)
+    pub fn new(interval: f32) -> Self{
+        Self{ interval, last: None }
+    }
+
+    /// 消息是否需要转发给游戏
+    pub fn accept(&mut self, msg: &MessageFromClient) -> bool{
+        let direction = match msg{
+            MessageFromClient::Turn(direction) => *direction,
+            _ => return true,
+        };
+        let now = Instant::now();
+        if let Some((last, time)) = self.last{
+            if last == direction && now.duration_since(time).as_secs_f32() < self.interval{
+                return false;
+            }
+        }
+        self.last = Some((direction, now));
+        true
+    }
+}
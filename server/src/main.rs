@@ -1,16 +1,29 @@
-use std::{env, sync::{Arc, Mutex}, collections::HashMap, net::SocketAddr, time::Duration};
+use std::{sync::{Arc, Mutex, atomic::{AtomicUsize, Ordering}}, collections::HashMap, net::SocketAddr, time::Duration};
 
-use bevy::{prelude::*, app::ScheduleRunnerSettings};
+use bevy::{prelude::*, app::ScheduleRunnerSettings, time::FixedTimestep};
 use futures_util::{StreamExt, SinkExt};
 use snake::*;
 use anyhow::Result;
 use log::info;
-use rand::random;
 use futures_channel::mpsc::{unbounded, UnboundedSender, UnboundedReceiver};
-use futures_util::{future, pin_mut, stream::TryStreamExt};
+use futures_util::{future::{self, Either}, pin_mut, stream::SplitStream};
 
 use tokio::{net::{TcpListener, TcpStream}, runtime::Runtime};
-use tungstenite::protocol::Message;
+use tokio_tungstenite::WebSocketStream;
+use tungstenite::protocol::{Message, CloseFrame, WebSocketConfig, frame::coding::CloseCode};
+
+mod args;
+mod bot;
+mod limit;
+mod replay;
+mod room;
+mod session;
+use args::ServerArgs;
+use bot::Bots;
+use limit::{ConnectionLimits, RateLimiter, TurnFilter};
+use replay::{Recorder, ReplayHeader, REPLAY_VERSION};
+use room::{Room, Rooms, DEFAULT_ROOM};
+use session::Sessions;
 
 type Tx = UnboundedSender<Message>;
 type PeerMap = Arc<Mutex<HashMap<SocketAddrWithUUID, Tx>>>;
@@ -27,122 +40,345 @@ impl SocketAddrWithUUID{
     }
 }
 
+/// 每个连接共用的服务器信息
+#[derive(Clone)]
+pub struct ServerInfo{
+    pub name: String,
+    pub config: GameConfig,
+    /// 每个连接的限流设置
+    pub limits: ConnectionLimits,
+    /// 连接计数, 用来给玩家分配颜色
+    pub connections: Arc<AtomicUsize>,
+    pub sessions: Arc<Mutex<Sessions>>,
+    /// 断线后保留玩家的时间
+    pub grace_period: Duration,
+}
+
+impl ServerInfo{
+    /// 生成发给新连接的欢迎信息
+    fn welcome(&self, player_id: String, session_token: String, resumed: bool) -> Welcome{
+        let count = self.connections.fetch_add(1, Ordering::Relaxed);
+        Welcome{
+            protocol_version: PROTOCOL_VERSION,
+            player_id,
+            color: PLAYER_COLORS[count % PLAYER_COLORS.len()],
+            server_name: self.name.clone(),
+            config: self.config.clone(),
+            session_token,
+            resumed,
+        }
+    }
+}
+
 fn main(){
-    App::new()
-    .insert_resource(ScheduleRunnerSettings::run_loop(Duration::from_secs_f64(
+    let args = match ServerArgs::parse(){
+        Err(err) => {
+            eprintln!("{:?}", err);
+            return;
+        }
+        Ok(v) => v
+    };
+
+    // 重放录像
+    if let Some(path) = args.replay.as_ref(){
+        if let Err(err) = replay::play(path, args.until){
+            eprintln!("录像重放失败: {:?}", err);
+        }
+        return;
+    }
+
+    // 记录种子, 用同样的种子可以重现游戏
+    let rng = match args.seed{
+        Some(seed) => GameRng::new(seed),
+        None => GameRng::from_entropy(),
+    };
+    println!("随机数种子:{}", rng.seed());
+
+    let mut app = App::new();
+
+    if let Some(path) = args.record.as_ref(){
+        let header = ReplayHeader{
+            version: REPLAY_VERSION,
+            seed: rng.seed(),
+            config: args.config.clone(),
+        };
+        match Recorder::create(path, &header){
+            Err(err) => {
+                eprintln!("无法创建录像文件 {path}: {:?}", err);
+                return;
+            }
+            Ok(recorder) => {
+                println!("录像保存到:{path}");
+                app.insert_resource(recorder);
+            }
+        }
+    }
+
+    if args.npcs > 0{
+        match Bots::new(args.npcs, &args.npc_brains, &args.npc_model, rng.seed()){
+            Err(err) => {
+                eprintln!("无法加载NPC: {:?}", err);
+                return;
+            }
+            Ok(bots) => {
+                println!("NPC数量:{}", args.npcs);
+                app.insert_resource(bots);
+            }
+        }
+    }
+
+    let tick_interval = args.config.tick_interval;
+
+    app.insert_resource(ScheduleRunnerSettings::run_loop(Duration::from_secs_f64(
         1.0 / 60.0,
     )))
+    .insert_resource(Rooms::new(args.config.clone(), rng.seed(), args.max_spectators))
+    .insert_resource(args.config.clone())
+    .insert_resource(args)
+    .insert_resource(MessageBacklog::default())
     .add_startup_system(setup_server)
     .add_plugins(HeadlessPlugins)
-    .add_plugin(SnakeGame)
+    .add_event::<SnakeMovementEvent>()
     .add_system(receive_message)
-    .add_system(sync_leader_board)
-    .add_system(sync_data.after(snake_movement))
+    .add_system_set(
+        SystemSet::new()
+            .with_run_criteria(FixedTimestep::step(tick_interval as f64))
+            .with_system(bot::drive_bots.before(step_rooms))
+            .with_system(step_rooms)
+    )
+    .add_system(replay::flush_recorder.after(step_rooms))
     .run();
 }
 
-/// 同步得分榜
-pub fn sync_leader_board(
-    leader_board: Res<LeaderBoard>,
+/// 推进所有房间的游戏, 把同步数据发给房间里的玩家
+/// 
+/// 平时只发送增量, 定期或者有客户端请求时发送关键帧
+pub fn step_rooms(
+    mut rooms: ResMut<Rooms>,
     message_sender: Res<MessageSender>,
-    mut event_reader: EventReader<SyncLeaderBoardEvent>){
-    if let Some(_) = event_reader.iter().next(){
-        let msg = IncomingMessage::ServerMessage(MessageFromServer::LeaderBoard(leader_board.clone()));
-        let _res = message_sender.unbounded_send(msg);
+    mut snake_move_event_writer: EventWriter<SnakeMovementEvent>){
+    for room in rooms.rooms.values_mut(){
+        let (events, msg) = room.step();
+        if room.members.is_empty(){
+            continue;
+        }
+        if events.contains(&GameEvent::LeaderBoardChanged){
+            send_leader_board(room, &message_sender);
+        }
+        for event in events{
+            // 通知死亡的玩家, 由玩家决定什么时候重新加入, 同时告诉房间里的所有人谁撞死了谁
+            if let GameEvent::PlayerDied(death) = event{
+                send_to_room(&message_sender, room, MessageFromServer::KillFeed{
+                    victim: death.player_name.clone(),
+                    killer: death.killer_name.clone(),
+                    cause: death.cause,
+                    killer_kills: death.killer_kills,
+                });
+                send_in_room(&message_sender, room, &death.player_id, MessageFromServer::PlayerDied{
+                    victim: death.player_name,
+                    killer: death.killer_name,
+                    cause: death.cause,
+                    final_length: death.final_length,
+                });
+            }
+        }
+        send_to_room(&message_sender, room, msg);
+    }
+    snake_move_event_writer.send(SnakeMovementEvent);
+}
+
+/// 同步得分榜给房间里的玩家
+fn send_leader_board(room: &Room, message_sender: &MessageSender){
+    send_to_room(message_sender, room, MessageFromServer::LeaderBoard(room.world.leader_board.clone()));
+}
+
+/// 发给一个玩家, tick是玩家所在房间的移动周期
+fn send_to(message_sender: &MessageSender, player_id: &str, tick: Option<u64>, message: MessageFromServer){
+    let frame = ServerFrame{ tick, message };
+    let _ = message_sender.unbounded_send(IncomingMessage::ServerMessageTo(player_id.to_string(), frame));
+}
+
+/// 发给房间里的一个玩家
+fn send_in_room(message_sender: &MessageSender, room: &Room, player_id: &str, message: MessageFromServer){
+    send_to(message_sender, player_id, Some(room.world.tick), message);
+}
+
+/// 发给房间里的所有人
+fn send_to_room(message_sender: &MessageSender, room: &Room, message: MessageFromServer){
+    let frame = ServerFrame{ tick: Some(room.world.tick), message };
+    let _ = message_sender.unbounded_send(IncomingMessage::ServerMessageToPlayers(room.member_list(), frame));
+}
+
+/// 拒绝玩家的请求并断开连接, 之后这个连接不会再收到房间的同步消息
+fn reject(message_sender: &MessageSender, player_id: &str, tick: Option<u64>, reason: String){
+    send_to(message_sender, player_id, tick, MessageFromServer::Rejected(reason.clone()));
+    let _ = message_sender.unbounded_send(IncomingMessage::CloseConnection(player_id.to_string(), reason));
+}
+
+/// 录像只记录默认房间中会改变游戏世界的消息
+fn record_message(recorder: Option<&mut Recorder>, room: &Room, player_id: &str, msg: &MessageFromClient){
+    let changes_world = matches!(msg, MessageFromClient::JoinGame(_)
+        | MessageFromClient::LeaveGame
+        | MessageFromClient::Turn(_)
+        | MessageFromClient::Disconnected
+        | MessageFromClient::Reconnected);
+    if !changes_world{
+        return;
+    }
+    if let (Some(recorder), DEFAULT_ROOM) = (recorder, room.id){
+        if let Err(err) = recorder.record(room.world.tick, player_id, msg){
+            error!("录像写入失败: {:?}", err);
+        }
     }
 }
 
-/// 给客户端发送同步数据
-pub fn sync_data(player_list: Res<PlayerList>,
-    snake_positions: Query<&Position, With<SnakeSegment>>,
-    mut event_reader: EventReader<SnakeMovementEvent>,
-    foods: Query<&Position, With<Food>>,
-    message_sender: Res<MessageSender>){
-    if let Some(_) = event_reader.iter().next(){
-        // 每个玩家，所有实体的坐标点数组
-        let mut players = HashMap::new();
-        for (id, player_info) in player_list.iter(){
-            // 循环玩家蛇头和所有蛇尾的Entity
-            let positions = player_info.snake_segments
-            .iter()
-            // 根据Entity查询到他们的所有Position
-            .filter_map(|e| snake_positions.get(*e).ok())
-            .map(|pos| *pos)
-            .collect::<Vec<Position>>();
-            players.insert(id.clone(), positions);
+/// 玩家进入房间, 然后告诉玩家房间信息和得分榜
+fn join_room(rooms: &mut Rooms, recorder: Option<&mut Recorder>, message_sender: &MessageSender, player_id: &str, room_id: u32){
+    if !rooms.rooms.contains_key(&room_id){
+        info!("{player_id} 进入的房间{room_id}不存在");
+        return;
+    }
+    // 离开默认房间时蛇会被删除, 录像中记为离开游戏
+    if let Some(room) = rooms.room_of(player_id){
+        if room.id != room_id{
+            record_message(recorder, room, player_id, &MessageFromClient::LeaveGame);
+        }
+    }
+    match rooms.join(player_id, room_id){
+        Ok(room) => {
+            send_in_room(message_sender, room, player_id, MessageFromServer::RoomJoined(room.info()));
+            send_in_room(message_sender, room, player_id, MessageFromServer::LeaderBoard(room.world.leader_board.clone()));
         }
-        let foods = foods.iter().map(|v| v.clone()).collect();
-        let msg = IncomingMessage::ServerMessage(MessageFromServer::SyncData(SyncData { players, foods }));
-        let _res = message_sender.unbounded_send(msg);
+        Err(err) => info!("{player_id} 无法进入房间: {:?}", err)
     }
 }
 
-/// 从websocket服务器接收数据
+/// 从websocket服务器接收数据, 每帧处理所有待处理的消息
 pub fn receive_message(
     mut message_receiver: ResMut<MessageReceiver>,
-    mut commands: Commands,
-    mut player_list: ResMut<PlayerList>,
-    mut sync_leader_board_writer: EventWriter<SyncLeaderBoardEvent>,
-    mut player_heads: Query<(&mut SnakeHead,  &PlayerId)>
+    mut backlog: ResMut<MessageBacklog>,
+    mut rooms: ResMut<Rooms>,
+    mut recorder: Option<ResMut<Recorder>>,
+    message_sender: Res<MessageSender>,
 ) {
-    let msg = match message_receiver.try_next(){
-        Ok(Some(v)) => v,
-        _ => return
-    };
-    match msg{
-        IncomingMessage::ClientMessage(msg) => {
-            match msg{
-                MessageFromClient::JoinGame((uuid, player_name)) => {
-                    //创建玩家，并生成它的蛇
-                    let player_info = PlayerInfo {
-                        snake_segments: vec![],
-                        player_id: uuid.clone(),
-                        player_name,
-                        spawn_pos: Position::new((random::<f32>() * ARENA_WIDTH as f32) as i32, 0),
-                        last_tail_position: None,
-                    };
-                    player_list.insert(uuid.clone(), player_info);
-                    spawn_snake(&mut commands, &mut player_list, uuid, SNAKE_HEAD_COLOR);
-
-                    sync_leader_board_writer.send(SyncLeaderBoardEvent);
-                },
-                MessageFromClient::LeaveGame(uuid) => {
-                    if let Some(player_info) = player_list.remove(&uuid){
-                        for seg in player_info.snake_segments{
-                            commands.entity(seg).despawn();
-                        }
+    for msg in message_receiver.drain(&mut backlog){
+        let (player_id, msg) = match msg{
+            IncomingMessage::PlayerMessage(player_id, msg) => (player_id, msg),
+            _ => continue
+        };
+        // 断线的观众在宽限期内不占用名额, 重连时名额已经被占满就断开
+        let online = match msg{
+            MessageFromClient::Disconnected => rooms.set_online(&player_id, false),
+            MessageFromClient::Reconnected => rooms.set_online(&player_id, true),
+            _ => Ok(())
+        };
+        if let Err(err) = online{
+            let tick = rooms.room_of(&player_id).map(|room| room.world.tick);
+            reject(&message_sender, &player_id, tick, err.to_string());
+            continue;
+        }
+        match msg{
+            MessageFromClient::ListRooms => {
+                let tick = rooms.room_of(&player_id).map(|room| room.world.tick);
+                send_to(&message_sender, &player_id, tick, MessageFromServer::RoomList(rooms.list()));
+            }
+            MessageFromClient::CreateRoom(name) => {
+                match rooms.create(name){
+                    Ok(room_id) => join_room(&mut rooms, recorder.as_deref_mut(), &message_sender, &player_id, room_id),
+                    Err(err) => info!("{player_id} 无法创建房间: {:?}", err)
+                }
+            }
+            MessageFromClient::JoinRoom(room_id) => join_room(&mut rooms, recorder.as_deref_mut(), &message_sender, &player_id, room_id),
+            MessageFromClient::LeaveGame => {
+                if let Some(room) = rooms.room_of(&player_id){
+                    record_message(recorder.as_deref_mut(), room, &player_id, &MessageFromClient::LeaveGame);
+                }
+                rooms.disconnect(&player_id);
+            }
+            MessageFromClient::Spectate => {
+                // 已经加入游戏的蛇会被删除, 录像中记为离开游戏
+                if let Some(room) = rooms.room_of(&player_id){
+                    record_message(recorder.as_deref_mut(), room, &player_id, &MessageFromClient::LeaveGame);
+                }
+                // 名额已满时断开连接, 不能继续当作普通玩家观看
+                let tick = rooms.room_of(&player_id).map(|room| room.world.tick);
+                match rooms.spectate(&player_id){
+                    Ok(()) => send_to(&message_sender, &player_id, tick, MessageFromServer::Spectating),
+                    Err(err) => reject(&message_sender, &player_id, tick, err.to_string()),
+                }
+            }
+            // 观众不能控制蛇
+            MessageFromClient::JoinGame(_) | MessageFromClient::Turn(_) if rooms.is_spectator(&player_id) => (),
+            msg => {
+                let room = match rooms.room_of(&player_id){
+                    Some(v) => v,
+                    None => continue
+                };
+                record_message(recorder.as_deref_mut(), room, &player_id, &msg);
+                if let MessageFromClient::RequestKeyframe | MessageFromClient::Reconnected = msg{
+                    room.keyframe_requested = true;
+                }
+                // 重连的玩家回到原来的房间, 继续控制原来的蛇
+                if let MessageFromClient::Reconnected = msg{
+                    send_in_room(&message_sender, room, &player_id, MessageFromServer::RoomJoined(room.info()));
+                    send_in_room(&message_sender, room, &player_id, MessageFromServer::LeaderBoard(room.world.leader_board.clone()));
+                    if let Some(snake) = room.world.snakes.get(&player_id){
+                        send_in_room(&message_sender, room, &player_id, MessageFromServer::Joined(snake.net_id));
                     }
-                    println!("游戏中的玩家数量:{}", player_list.len());
-                },
-                MessageFromClient::KeyEvent((uuid, key)) =>{
-                    for (mut head, player_id) in player_heads.iter_mut(){
-                        if player_id.id == uuid{
-                            let dir = if key == "L" {
-                                snake::Direction::Left
-                            } else if key == "D" {
-                                snake::Direction::Down
-                            } else if key == "U" {
-                                snake::Direction::Up
-                            } else if key == "R"{
-                                snake::Direction::Right
-                            } else {
-                                head.direction
-                            };
-                            if dir != head.direction.opposite() {
-                                head.direction = dir;
+                }
+                for event in apply_client_message(&mut room.world, &mut room.inputs, player_id, msg){
+                    match event{
+                        GameEvent::LeaderBoardChanged => send_leader_board(room, &message_sender),
+                        // 告诉刚加入的玩家自己的蛇的数字id
+                        GameEvent::PlayerSpawned { player_id } => {
+                            if let Some(snake) = room.world.snakes.get(&player_id){
+                                send_in_room(&message_sender, room, &player_id, MessageFromServer::Joined(snake.net_id));
                             }
-                            break;
                         }
+                        _ => ()
                     }
                 }
-                _ => ()
+            }
+        }
+    }
+}
+
+/// 把玩家发来的消息作用到游戏世界, 重放录像时也使用这个函数
+/// 
+/// 方向输入放入inputs中, 在下一个移动周期生效
+pub fn apply_client_message(world: &mut GameState, inputs: &mut Vec<(String, snake::Direction)>, player_id: String, msg: MessageFromClient) -> Vec<GameEvent>{
+    match msg{
+        MessageFromClient::JoinGame(player_name) => {
+            //创建玩家，并生成它的蛇
+            match world.add_player(player_id, player_name){
+                Ok(events) => events,
+                Err(err) => {
+                    info!("无法加入游戏: {:?}", err);
+                    vec![]
+                }
             }
         },
-        IncomingMessage::ServerMessage(_) => todo!(),
+        MessageFromClient::LeaveGame => {
+            world.remove_player(&player_id);
+            vec![]
+        },
+        MessageFromClient::Turn(direction) =>{
+            inputs.push((player_id, direction));
+            vec![]
+        }
+        MessageFromClient::Disconnected => {
+            world.set_paused(&player_id, true);
+            vec![]
+        }
+        MessageFromClient::Reconnected => {
+            world.set_paused(&player_id, false);
+            vec![]
+        }
+        _ => vec![]
     }
 }
 
-fn setup_server(mut commands: Commands){
+fn setup_server(mut commands: Commands, args: Res<ServerArgs>){
 
     let (sender, receiver) = unbounded::<IncomingMessage>();
     let (sender1, receiver1) = unbounded::<IncomingMessage>();
@@ -157,9 +393,18 @@ fn setup_server(mut commands: Commands){
         Ok(v) => v,
     };
 
+    let addr = args.addr.clone();
+    let server_info = ServerInfo{
+        name: args.name.clone(),
+        config: args.config.clone(),
+        limits: args.limits.clone(),
+        connections: Arc::new(AtomicUsize::new(0)),
+        sessions: Arc::new(Mutex::new(Sessions::default())),
+        grace_period: Duration::from_secs_f32(args.grace_period),
+    };
     std::thread::spawn(move ||{
         rt.block_on(async {
-            match start_server(sender, receiver1).await{
+            match start_server(addr, server_info, sender, receiver1).await{
                 Ok(()) => info!("websocket服务器结束"),
                 Err(err) => error!("websocket服务器出错: {:?}", err)
             };
@@ -167,9 +412,7 @@ fn setup_server(mut commands: Commands){
     });
 }
 
-async fn start_server(sender: UnboundedSender<IncomingMessage>, mut receiver: UnboundedReceiver<IncomingMessage>) -> Result<()> {
-    let addr = env::args().nth(1).unwrap_or_else(|| "127.0.0.1:8080".to_string());
-
+async fn start_server(addr: String, server_info: ServerInfo, sender: UnboundedSender<IncomingMessage>, mut receiver: UnboundedReceiver<IncomingMessage>) -> Result<()> {
     let state = PeerMap::new(Mutex::new(HashMap::new()));
 
     // 创建我们将接受连接的事件循环和 TCP 侦听器。
@@ -177,36 +420,60 @@ async fn start_server(sender: UnboundedSender<IncomingMessage>, mut receiver: Un
     let listener = try_socket.expect("Failed to bind");
     info!("Listening on: {}", addr);
 
-    // 广播：从游戏服务器发送过来的每一个消息，转发给每一个客户端
+    // 从游戏服务器发送过来的每一个消息，转发给对应的客户端
     let peer_map = state.clone();
     tokio::spawn(async move {
         while let Some(msg) = receiver.next().await {
-            // info!("需要广播1条消息");
-            let peers = peer_map.lock().unwrap();
+            let mut peers = peer_map.lock().unwrap();
             
-            if let IncomingMessage::ServerMessage(msg) = msg{
-                // let mut broadcast_count = 0;
-                for (_addr, recp) in peers.iter() {
-                    recp.unbounded_send(Message::Binary(bincode::serialize(&msg).unwrap())).unwrap();
-                    // broadcast_count += 1;
+            // 发送失败的连接已经关闭, 从PeerMap中删除
+            match msg{
+                IncomingMessage::ServerMessageTo(id, msg) => {
+                    let data = encode(&msg);
+                    peers.retain(|key, recp| key.id != id || send_to_peer(key, recp, Message::Binary(data.clone())));
                 }
-                // info!("给{broadcast_count}个客户端广播了消息: {:?}", msg);
+                IncomingMessage::ServerMessageToPlayers(ids, msg) => {
+                    let data = encode(&msg);
+                    peers.retain(|key, recp| !ids.contains(&key.id) || send_to_peer(key, recp, Message::Binary(data.clone())));
+                }
+                IncomingMessage::CloseConnection(id, reason) => {
+                    let frame = CloseFrame{ code: CloseCode::Policy, reason: reason.into() };
+                    peers.retain(|key, recp| key.id != id || send_to_peer(key, recp, Message::Close(Some(frame.clone()))));
+                }
+                IncomingMessage::ServerMessage(_) | IncomingMessage::ClientMessage(_) | IncomingMessage::PlayerMessage(..) => ()
             }
         }
     });
 
     // 在单独的任务中生成每个连接的处理
     while let Ok((stream, addr)) = listener.accept().await {
-        tokio::spawn(handle_connection(state.clone(), stream, addr, sender.clone()));
+        tokio::spawn(handle_connection(state.clone(), stream, addr, server_info.clone(), sender.clone()));
     }
 
     Ok(())
 }
 
-async fn handle_connection(peer_map: PeerMap, raw_stream: TcpStream, addr: SocketAddr, sender: UnboundedSender<IncomingMessage>) {
+/// 把消息放进连接的发送队列, 连接已经关闭时记录错误并返回false
+fn send_to_peer(key: &SocketAddrWithUUID, recp: &Tx, msg: Message) -> bool{
+    match recp.unbounded_send(msg){
+        Ok(()) => true,
+        Err(err) => {
+            error!("{} 发送消息失败, 删除连接: {:?}", key.addr, err);
+            false
+        }
+    }
+}
+
+async fn handle_connection(peer_map: PeerMap, raw_stream: TcpStream, addr: SocketAddr, server_info: ServerInfo, sender: UnboundedSender<IncomingMessage>) {
     info!("收到TCP连接: {}", addr);
 
-    let ws_stream = match tokio_tungstenite::accept_async(raw_stream).await{
+    // 超过大小的消息会让连接出错断开
+    let ws_config = WebSocketConfig{
+        max_message_size: Some(server_info.limits.max_message_size),
+        max_frame_size: Some(server_info.limits.max_message_size),
+        ..Default::default()
+    };
+    let ws_stream = match tokio_tungstenite::accept_async_with_config(raw_stream, Some(ws_config)).await{
         Err(err) => {
             error!("websocket握手时出现错误:{:?}", err);
             return;
@@ -214,33 +481,82 @@ async fn handle_connection(peer_map: PeerMap, raw_stream: TcpStream, addr: Socke
         Ok(v) => v
     };
     info!("已建立网络套接字连接: {}", addr);
-    let id = uuid::Uuid::new_v4().to_string();
+
+    let (mut outgoing, mut incoming) = ws_stream.split();
+
+    // 协议版本不兼容时断开连接, 原因放在Close帧中, 任何版本的客户端都能显示
+    let session = match wait_hello(&mut incoming).await{
+        Err(reason) => {
+            info!("{} 拒绝连接: {reason}", addr);
+            let frame = CloseFrame{ code: CloseCode::Policy, reason: reason.into() };
+            let _ = outgoing.send(Message::Close(Some(frame))).await;
+            return;
+        }
+        Ok(v) => v
+    };
+
+    // 宽限期内带着令牌重连, 继续使用原来的玩家id
+    let resumed = session.and_then(|token| {
+        let id = server_info.sessions.lock().unwrap().resume(&token)?;
+        Some((token, id))
+    });
+    let (token, id, resumed) = match resumed{
+        Some((token, id)) => (token, id, true),
+        None => {
+            let (token, id) = server_info.sessions.lock().unwrap().create();
+            (token, id, false)
+        }
+    };
 
     // Insert the write part of this peer to the peer map.
     let (tx, rx) = unbounded();
 
-    // Sender放入peermap中
-    peer_map.lock().unwrap().insert(SocketAddrWithUUID::new(addr, id.clone()), tx);
+    // 回复uid和游戏设置, 放入PeerMap之前先放进发送队列, 保证是连接收到的第一条消息。
+    // 这时还没有进入房间, 不带移动周期
+    let msg = ServerFrame{ tick: None, message: MessageFromServer::OnConnected(server_info.welcome(id.clone(), token.clone(), resumed)) };
+    let _ = tx.unbounded_send(Message::Binary(encode(&msg)));
 
-    let (mut outgoing, incoming) = ws_stream.split();
+    // 发送Close帧用的Sender, 超过限制时通过它告诉客户端原因
+    let close_tx = tx.clone();
 
-    // 回复uid
-    let msg = MessageFromServer::OnConnected(id.clone());
-    outgoing.send(Message::Binary(bincode::serialize(&msg).unwrap())).await.unwrap();
+    // Sender放入peermap中
+    peer_map.lock().unwrap().insert(SocketAddrWithUUID::new(addr, id.clone()), tx);
 
-    // 接收消息的Future
-    let broadcast_incoming = incoming.try_for_each(|msg| {
-        // info!("收到一个消息 {}: {:?}", addr, msg);
+    if resumed{
+        info!("{} 恢复会话: {id}", addr);
+        sender.unbounded_send(IncomingMessage::PlayerMessage(id.clone(), MessageFromClient::Reconnected)).unwrap();
+    }else{
+        // 先进入默认房间
+        sender.unbounded_send(IncomingMessage::PlayerMessage(id.clone(), MessageFromClient::JoinRoom(DEFAULT_ROOM))).unwrap();
+    }
 
-        if let Message::Binary(msg) = msg{
-            if let Ok(msg) = bincode::deserialize::<MessageFromClient>(&msg){
-                // info!("消息转发给了游戏服务器: {:?}", msg);
-                sender.unbounded_send(IncomingMessage::ClientMessage(msg)).unwrap();
+    // 接收消息的Future, 超过限制时返回断开的原因
+    let limits = &server_info.limits;
+    let broadcast_incoming = async {
+        let mut rate_limiter = RateLimiter::new(limits);
+        let mut turn_filter = TurnFilter::new(server_info.config.tick_interval);
+        while let Some(Ok(msg)) = incoming.next().await{
+            // info!("收到一个消息 {}: {:?}", addr, msg);
+            if !rate_limiter.try_acquire(){
+                return Err("发送消息过快".to_string());
+            }
+            if let Message::Binary(msg) = msg{
+                if let Ok(msg) = decode::<MessageFromClient>(&msg){
+                    // 服务器内部使用的消息不能由客户端发送
+                    if matches!(msg, MessageFromClient::Disconnected | MessageFromClient::Reconnected){
+                        continue;
+                    }
+                    if !turn_filter.accept(&msg){
+                        continue;
+                    }
+                    // info!("消息转发给了游戏服务器: {:?}", msg);
+                    // 玩家id由连接决定, 客户端不能冒充其他玩家
+                    sender.unbounded_send(IncomingMessage::PlayerMessage(id.clone(), msg)).unwrap();
+                }
             }
         }
-
-        future::ok(())
-    });
+        Ok(())
+    };
 
     // PeerMap中发送的消息，转发到每个客户端的outgoing输出流
     let receive_from_others = rx.map(Ok).forward(outgoing);
@@ -248,13 +564,53 @@ async fn handle_connection(peer_map: PeerMap, raw_stream: TcpStream, addr: Socke
     pin_mut!(broadcast_incoming, receive_from_others);
 
     // 等待两个Future都完成
-    future::select(broadcast_incoming, receive_from_others).await;
+    if let Either::Left((Err(reason), receive_from_others)) = future::select(broadcast_incoming, receive_from_others).await{
+        info!("{} 超过限制, 断开连接: {reason}", addr);
+        let frame = CloseFrame{ code: CloseCode::Policy, reason: reason.into() };
+        let _ = close_tx.unbounded_send(Message::Close(Some(frame)));
+        // 所有Sender都删除以后转发结束, 等待Close帧发送出去
+        peer_map.lock().unwrap().remove(&SocketAddrWithUUID::new(addr, id.clone()));
+        drop(close_tx);
+        let _ = tokio::time::timeout(Duration::from_secs(1), receive_from_others).await;
+    }
 
     info!("{} 连接断开", &addr);
 
-    //删除玩家数据
-    sender.unbounded_send(IncomingMessage::ClientMessage(MessageFromClient::LeaveGame(id.clone()))).unwrap();
+    // 蛇暂停在原地, 宽限期内没有重连再删除玩家数据
+    let generation = server_info.sessions.lock().unwrap().disconnect(&token);
+    sender.unbounded_send(IncomingMessage::PlayerMessage(id.clone(), MessageFromClient::Disconnected)).unwrap();
+    let sessions = server_info.sessions.clone();
+    let grace_period = server_info.grace_period;
+    let sender = sender.clone();
+    tokio::spawn(async move {
+        tokio::time::sleep(grace_period).await;
+        let expired = sessions.lock().unwrap().expire(&token, generation);
+        if let Some(id) = expired{
+            info!("会话过期: {id}");
+            let _ = sender.unbounded_send(IncomingMessage::PlayerMessage(id, MessageFromClient::LeaveGame));
+        }
+    });
 
-    peer_map.lock().unwrap().remove(&SocketAddrWithUUID { addr, id });
+    peer_map.lock().unwrap().remove(&SocketAddrWithUUID::new(addr, id.clone()));
     println!("当前在线玩家:{:?}", peer_map.lock().unwrap().keys().len());
-}
\ No newline at end of file
+}
+/// 等待客户端发送Hello, 返回客户端带来的会话令牌, 协议版本不兼容时返回拒绝的原因
+async fn wait_hello(incoming: &mut SplitStream<WebSocketStream<TcpStream>>) -> Result<Option<String>, String>{
+    let msg = match tokio::time::timeout(Duration::from_secs(10), incoming.next()).await{
+        Err(_) => return Err("等待Hello超时".to_string()),
+        Ok(None) | Ok(Some(Err(_))) => return Err("连接已断开".to_string()),
+        Ok(Some(Ok(msg))) => msg
+    };
+    let data = match msg{
+        Message::Binary(data) => data,
+        _ => return Err("第一条消息必须是Hello".to_string())
+    };
+    match decode::<MessageFromClient>(&data){
+        Ok(MessageFromClient::Hello { version, session }) => {
+            check_version(version).map_err(|err| err.to_string())?;
+            Ok(session)
+        }
+        Ok(_) => Err("第一条消息必须是Hello".to_string()),
+        Err(err) => Err(err.to_string()),
+    }
+}
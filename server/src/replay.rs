@@ -0,0 +1,128 @@
+//! 游戏录像
+//!
+//! 录像文件开头是[`ReplayHeader`], 后面是服务器收到的每一条客户端消息、发送消息的玩家和收到时的移动周期。
+//! 游戏规则和随机数都是确定的, 用同样的种子把这些消息按顺序重新作用到[`GameState`]就能重现整局游戏。
+
+use std::{fs::File, io::{BufReader, BufWriter, ErrorKind, Write}};
+
+use anyhow::{anyhow, Result};
+use bevy::prelude::*;
+use bincode::Options;
+use serde::{Serialize, Deserialize};
+use snake::*;
+
+use crate::apply_client_message;
+
+/// 录像文件格式版本
+pub const REPLAY_VERSION: u32 = 3;
+
+/// 录像文件头
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ReplayHeader{
+    pub version: u32,
+    pub seed: u64,
+    pub config: GameConfig,
+}
+
+/// 在第tick个移动周期之后收到的玩家消息
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ReplayRecord{
+    pub tick: u64,
+    pub player_id: String,
+    pub message: MessageFromClient,
+}
+
+/// 整数使用变长编码, 让录像文件更小
+fn options() -> impl Options{
+    bincode::DefaultOptions::new()
+}
+
+/// 录像: 把服务器收到的所有客户端消息写入文件
+#[derive(Resource)]
+pub struct Recorder{
+    writer: BufWriter<File>,
+}
+
+impl Recorder{
+    pub fn create(path: &str, header: &ReplayHeader) -> Result<Self>{
+        let mut writer = BufWriter::new(File::create(path)?);
+        options().serialize_into(&mut writer, header)?;
+        Ok(Self{ writer })
+    }
+
+    pub fn record(&mut self, tick: u64, player_id: &str, message: &MessageFromClient) -> Result<()>{
+        // 元组和ReplayRecord的编码相同, 这样不需要复制消息
+        options().serialize_into(&mut self.writer, &(tick, player_id, message))?;
+        Ok(())
+    }
+}
+
+/// 每个移动周期把录像写入磁盘
+pub fn flush_recorder(
+    recorder: Option<ResMut<Recorder>>,
+    mut event_reader: EventReader<SnakeMovementEvent>){
+    if let (Some(mut recorder), Some(_)) = (recorder, event_reader.iter().next()){
+        if let Err(err) = recorder.writer.flush(){
+            error!("录像写入失败: {:?}", err);
+        }
+    }
+}
+
+/// 重放录像, 打印出所有玩家的加入、离开和死亡
+pub fn play(path: &str, until: Option<u64>) -> Result<()>{
+    let mut reader = BufReader::new(File::open(path)?);
+    let header: ReplayHeader = options().deserialize_from(&mut reader)?;
+    if header.version != REPLAY_VERSION{
+        return Err(anyhow!("不支持的录像版本: {}", header.version));
+    }
+    println!("录像: {:?}", header);
+
+    let mut world = GameState::new(header.config, GameRng::new(header.seed));
+    let mut inputs = vec![];
+
+    loop{
+        let record = match options().deserialize_from::<_, ReplayRecord>(&mut reader){
+            Ok(v) => v,
+            Err(err) => match *err{
+                bincode::ErrorKind::Io(ref err) if err.kind() == ErrorKind::UnexpectedEof => break,
+                _ => return Err(err.into()),
+            }
+        };
+        if until.map(|until| record.tick > until).unwrap_or(false){
+            break;
+        }
+
+        while world.tick < record.tick{
+            step(&mut world, &mut inputs);
+        }
+
+        let uuid = &record.player_id;
+        match &record.message{
+            MessageFromClient::JoinGame(player_name) => println!("[{}] 玩家[{player_name}]加入游戏 {uuid}", world.tick),
+            MessageFromClient::LeaveGame => println!("[{}] 玩家离开游戏 {uuid}", world.tick),
+            _ => ()
+        }
+        apply_client_message(&mut world, &mut inputs, record.player_id, record.message);
+    }
+
+    if let Some(until) = until{
+        while world.tick < until{
+            step(&mut world, &mut inputs);
+        }
+    }
+
+    println!("重放结束, 移动周期:{}", world.tick);
+    for snake in world.snakes.values(){
+        println!("玩家[{}] 长度:{} 蛇头:{:?} 方向:{:?}", snake.player_name, snake.body.len(), snake.head(), snake.direction);
+    }
+    Ok(())
+}
+
+fn step(world: &mut GameState, inputs: &mut Vec<(String, snake::Direction)>){
+    for event in world.step(inputs){
+        if let GameEvent::PlayerDied(death) = event{
+            println!("[{}] 玩家[{}]死亡 {} {:?} 长度:{} 凶手:{:?}", world.tick, death.player_name, death.player_id, death.cause, death.final_length, death.killer_name);
+        }
+    }
+    inputs.clear();
+}
@@ -0,0 +1,217 @@
+//! 房间
+//!
+//! 每个房间有独立的游戏世界(场地、食物、得分榜), 同步消息只发给房间里的玩家。
+//! 连接成功后玩家先进入默认房间, 可以创建新房间或者进入其他房间。
+//! 除了默认房间, 最后一个玩家离开后房间会被删除。
+//!
+//! 观众也是房间的成员, 能收到同步消息和得分榜, 但是不能加入游戏。观众数量单独限制,
+//! 断线的观众在宽限期内不占用名额。
+
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+
+use anyhow::{anyhow, Result};
+use bevy::prelude::Resource;
+use snake::*;
+
+/// 默认房间的id, 默认房间一直存在
+pub const DEFAULT_ROOM: u32 = 0;
+/// 最多同时存在的房间数量
+pub const MAX_ROOMS: usize = 32;
+/// 房间名最多字符数
+pub const MAX_ROOM_NAME: usize = 16;
+
+pub struct Room{
+    pub id: u32,
+    pub name: String,
+    pub world: GameState,
+    /// 下一个移动周期要处理的玩家方向输入
+    pub inputs: Vec<(String, snake::Direction)>,
+    /// 房间里的所有玩家(uuid), 包括还没有加入游戏的和观众
+    pub members: BTreeSet<String>,
+    /// 上一次同步给客户端的状态, 用来计算增量
+    pub last_sync: SyncState,
+    /// 有客户端请求关键帧, 下一次同步时发送
+    pub keyframe_requested: bool,
+}
+
+impl Room{
+    fn new(id: u32, name: String, world: GameState) -> Self{
+        Self{
+            id,
+            name,
+            world,
+            inputs: vec![],
+            members: BTreeSet::new(),
+            last_sync: SyncState::default(),
+            keyframe_requested: false,
+        }
+    }
+
+    pub fn info(&self) -> RoomInfo{
+        RoomInfo{
+            id: self.id,
+            name: self.name.clone(),
+            players: self.world.snakes.len(),
+            max_players: self.world.config.max_players,
+        }
+    }
+
+    pub fn member_list(&self) -> Vec<String>{
+        self.members.iter().cloned().collect()
+    }
+
+    /// 推进一个移动周期, 返回这个周期的事件和要发给房间玩家的同步消息
+    pub fn step(&mut self) -> (Vec<GameEvent>, MessageFromServer){
+        let events = self.world.step(&self.inputs);
+        self.inputs.clear();
+
+        let mut current = SyncState::from_world(&self.world);
+        current.seq = self.last_sync.seq + 1;
+        let msg = if self.keyframe_requested || self.world.tick % KEYFRAME_INTERVAL == 0{
+            self.keyframe_requested = false;
+            MessageFromServer::SyncData(current.keyframe())
+        }else{
+            MessageFromServer::SyncDelta(self.last_sync.delta(&current))
+        };
+        self.last_sync = current;
+        (events, msg)
+    }
+}
+
+/// 服务器上的所有房间
+#[derive(Resource)]
+pub struct Rooms{
+    pub rooms: BTreeMap<u32, Room>,
+    /// 玩家(uuid)所在的房间
+    player_rooms: HashMap<String, u32>,
+    /// 所有观众(uuid)
+    spectators: HashSet<String>,
+    /// 断线后还在宽限期内的玩家(uuid)
+    offline: HashSet<String>,
+    max_spectators: usize,
+    config: GameConfig,
+    /// 每个房间的随机数种子是 seed + 房间id, 默认房间的种子就是seed, 可以用录像重放
+    seed: u64,
+    next_id: u32,
+}
+
+impl Rooms{
+    pub fn new(config: GameConfig, seed: u64, max_spectators: usize) -> Self{
+        let world = GameState::new(config.clone(), GameRng::new(seed));
+        let mut rooms = BTreeMap::new();
+        rooms.insert(DEFAULT_ROOM, Room::new(DEFAULT_ROOM, "默认房间".to_string(), world));
+        Self{
+            rooms,
+            player_rooms: HashMap::new(),
+            spectators: HashSet::new(),
+            offline: HashSet::new(),
+            max_spectators,
+            config,
+            seed,
+            next_id: DEFAULT_ROOM + 1,
+        }
+    }
+
+    /// 创建房间, 返回房间id
+    pub fn create(&mut self, name: String) -> Result<u32>{
+        if self.rooms.len() >= MAX_ROOMS{
+            return Err(anyhow!("房间数量已满({MAX_ROOMS})"));
+        }
+        let name = name.trim();
+        if name.is_empty() || name.chars().count() > MAX_ROOM_NAME{
+            return Err(anyhow!("房间名需要1~{MAX_ROOM_NAME}个字符"));
+        }
+        let id = self.next_id;
+        self.next_id += 1;
+        let rng = GameRng::new(self.seed.wrapping_add(id as u64));
+        let world = GameState::new(self.config.clone(), rng);
+        self.rooms.insert(id, Room::new(id, name.to_string(), world));
+        Ok(id)
+    }
+
+    pub fn list(&self) -> Vec<RoomInfo>{
+        self.rooms.values().map(|room| room.info()).collect()
+    }
+
+    pub fn room_of(&mut self, player_id: &str) -> Option<&mut Room>{
+        let id = self.player_rooms.get(player_id)?;
+        self.rooms.get_mut(id)
+    }
+
+    /// 进入房间, 先离开当前所在的房间
+    pub fn join(&mut self, player_id: &str, room_id: u32) -> Result<&mut Room>{
+        if !self.rooms.contains_key(&room_id){
+            return Err(anyhow!("房间{room_id}不存在"));
+        }
+        if self.player_rooms.get(player_id) != Some(&room_id){
+            self.leave(player_id);
+        }
+        self.player_rooms.insert(player_id.to_string(), room_id);
+        let room = self.rooms.get_mut(&room_id).unwrap();
+        room.members.insert(player_id.to_string());
+        // 新进入的玩家需要关键帧
+        room.keyframe_requested = true;
+        Ok(room)
+    }
+
+    /// 成为观众, 已经在游戏中的蛇会被删除
+    pub fn spectate(&mut self, player_id: &str) -> Result<()>{
+        if self.spectators.contains(player_id){
+            return Ok(());
+        }
+        if self.online_spectators() >= self.max_spectators{
+            return Err(anyhow!("观众数量已满({})", self.max_spectators));
+        }
+        if let Some(room) = self.room_of(player_id){
+            room.world.remove_player(player_id);
+        }
+        self.spectators.insert(player_id.to_string());
+        Ok(())
+    }
+
+    pub fn is_spectator(&self, player_id: &str) -> bool{
+        self.spectators.contains(player_id)
+    }
+
+    /// 在线的观众数量
+    fn online_spectators(&self) -> usize{
+        self.spectators.iter().filter(|id| !self.offline.contains(*id)).count()
+    }
+
+    /// 连接断开或者在宽限期内恢复
+    ///
+    /// 断线的观众不占用名额, 恢复连接时名额已经被别人占满就返回错误
+    pub fn set_online(&mut self, player_id: &str, online: bool) -> Result<()>{
+        if !online{
+            self.offline.insert(player_id.to_string());
+            return Ok(());
+        }
+        if self.offline.contains(player_id) && self.is_spectator(player_id) && self.online_spectators() >= self.max_spectators{
+            return Err(anyhow!("观众数量已满({})", self.max_spectators));
+        }
+        self.offline.remove(player_id);
+        Ok(())
+    }
+
+    /// 宽限期结束或者主动离开, 删除玩家的所有数据
+    pub fn disconnect(&mut self, player_id: &str){
+        self.leave(player_id);
+        self.spectators.remove(player_id);
+        self.offline.remove(player_id);
+    }
+
+    /// 离开当前房间(掉线或者换房间), 蛇从游戏中删除
+    pub fn leave(&mut self, player_id: &str){
+        let room_id = match self.player_rooms.remove(player_id){
+            Some(v) => v,
+            None => return
+        };
+        if let Some(room) = self.rooms.get_mut(&room_id){
+            room.world.remove_player(player_id);
+            room.members.remove(player_id);
+            if room.members.is_empty() && room_id != DEFAULT_ROOM{
+                self.rooms.remove(&room_id);
+            }
+        }
+    }
+}
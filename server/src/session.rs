@@ -0,0 +1,62 @@
+//! 会话
+//!
+//! 握手成功后服务器给每个连接一个会话令牌。连接断开后玩家的蛇暂停在原地,
+//! 宽限期内带着令牌重新连接可以继续控制原来的蛇, 超过宽限期才删除玩家。
+
+use std::collections::HashMap;
+
+struct Session{
+    player_id: String,
+    connected: bool,
+    /// 每次断开连接加1, 宽限期结束时用来判断期间有没有重连过
+    generation: u64,
+}
+
+#[derive(Default)]
+pub struct Sessions{
+    sessions: HashMap<String, Session>,
+}
+
+impl Sessions{
+    /// 创建新会话, 返回(令牌, 玩家id)
+    pub fn create(&mut self) -> (String, String){
+        let token = uuid::Uuid::new_v4().simple().to_string();
+        let player_id = uuid::Uuid::new_v4().to_string();
+        self.sessions.insert(token.clone(), Session{ player_id: player_id.clone(), connected: true, generation: 0 });
+        (token, player_id)
+    }
+
+    /// 用令牌恢复断开的会话, 返回玩家id
+    ///
+    /// 令牌不存在、已过期或者会话仍然在线(比如同时打开了两个页面)时返回None
+    pub fn resume(&mut self, token: &str) -> Option<String>{
+        let session = self.sessions.get_mut(token)?;
+        if session.connected{
+            return None;
+        }
+        session.connected = true;
+        Some(session.player_id.clone())
+    }
+
+    /// 连接断开, 返回这次断开的编号
+    pub fn disconnect(&mut self, token: &str) -> u64{
+        match self.sessions.get_mut(token){
+            Some(session) => {
+                session.connected = false;
+                session.generation += 1;
+                session.generation
+            }
+            None => 0
+        }
+    }
+
+    /// 宽限期结束, 如果玩家一直没有重连, 删除会话并返回玩家id
+    pub fn expire(&mut self, token: &str, generation: u64) -> Option<String>{
+        match self.sessions.get(token){
+            Some(session) if !session.connected && session.generation == generation => {
+                self.sessions.remove(token).map(|session| session.player_id)
+            }
+            _ => None
+        }
+    }
+}
@@ -0,0 +1,208 @@
+//! Bevy中使用的组件、资源和系统
+//!
+//! 服务器和网页客户端使用, 只在打开`bevy`特性(默认打开)时编译。NPC训练程序只需要[`GameState`](crate::GameState),
+//! 关闭默认特性可以不编译Bevy。
+
+use std::collections::HashMap;
+use bevy::{prelude::*, time::TimePlugin, app::{PluginGroupBuilder, ScheduleRunnerPlugin}, log::LogPlugin};
+use futures_channel::mpsc::{UnboundedSender, UnboundedReceiver};
+
+use crate::{Direction, GameConfig, IncomingMessage, Position};
+
+/// 蛇头颜色
+pub const SNAKE_HEAD_COLOR: Color = Color::rgb(0.7, 0.7, 0.7);
+pub const SNAKE_HEAD_COLOR_CURRENT: Color = Color::YELLOW;
+/// 食物fec938
+pub const FOOD_COLOR: Color = Color::rgb(1.0, 0.0, 1.0);
+/// 蛇身颜色
+const SNAKE_SEGMENT_COLOR: Color = Color::rgb(0.3, 0.3, 0.3);
+#[derive(Component)]
+pub struct Size {
+    width: f32,
+    height: f32,
+}
+impl Size {
+    pub fn square(x: f32) -> Self {
+        Self {
+            width: x,
+            height: x,
+        }
+    }
+}
+
+/// 蛇头
+#[derive(Component, Debug)]
+pub struct SnakeHead{
+    pub direction: Direction,
+}
+
+#[derive(Component)]
+pub struct SnakeSegment;
+
+/// 玩家列表, 使用蛇的数字id
+#[derive(Resource, Default, Deref, DerefMut)]
+pub struct PlayerList(HashMap<u32, PlayerInfo>);
+/// 向外部发送消息
+#[derive(Resource, Deref, DerefMut)]
+pub struct MessageSender(UnboundedSender<IncomingMessage>);
+impl MessageSender{
+    pub fn new(sender: UnboundedSender<IncomingMessage>) -> Self{
+        Self(sender)
+    }
+}
+
+/// 每帧最多处理的消息数量, 超过的留到下一帧
+pub const MAX_MESSAGES_PER_FRAME: usize = 500;
+
+/// 消息积压统计
+#[derive(Resource, Default, Debug)]
+pub struct MessageBacklog{
+    /// 上一帧处理的消息数量
+    pub processed: usize,
+    /// 连续处理到上限的帧数, 大于0说明消息处理不过来
+    pub saturated_frames: u32,
+}
+
+/// 接收外部消息
+#[derive(Resource, Deref, DerefMut)]
+pub struct MessageReceiver(UnboundedReceiver<IncomingMessage>);
+impl MessageReceiver{
+    pub fn new(receiver: UnboundedReceiver<IncomingMessage>) -> Self{
+        Self(receiver)
+    }
+
+    /// 取出所有待处理的消息, 最多MAX_MESSAGES_PER_FRAME条
+    pub fn drain(&mut self, backlog: &mut MessageBacklog) -> Vec<IncomingMessage>{
+        let mut messages = vec![];
+        while messages.len() < MAX_MESSAGES_PER_FRAME{
+            match self.try_next(){
+                Ok(Some(msg)) => messages.push(msg),
+                _ => break
+            }
+        }
+        backlog.processed = messages.len();
+        if messages.len() == MAX_MESSAGES_PER_FRAME{
+            backlog.saturated_frames += 1;
+            // 持续积压时大约每秒提醒一次
+            if backlog.saturated_frames % 60 == 1{
+                warn!("消息积压: 连续{}帧处理了{}条消息", backlog.saturated_frames, MAX_MESSAGES_PER_FRAME);
+            }
+        }else{
+            backlog.saturated_frames = 0;
+        }
+        messages
+    }
+}
+
+pub struct PlayerInfo{
+    pub snake_segments: Vec<Entity>,
+    pub player_id: u32,
+    pub player_name: String,
+    pub spawn_pos: Position,
+    pub last_tail_position: Option<Position>
+}
+
+/// 玩家信息
+#[derive(Component, Debug)]
+pub struct PlayerId{
+    pub id: u32
+}
+impl PlayerId{
+    pub fn new(id: u32) -> Self{
+        Self{
+            id
+        }
+    }
+}
+
+#[derive(Component)]
+pub struct Food;
+
+/// 所有房间推进了一个移动周期
+pub struct SnakeMovementEvent;
+
+pub fn camera_setup(mut commands: Commands) {
+    commands.spawn(Camera2dBundle::default());
+}
+
+/// 创建小蛇
+pub fn spawn_snake(mut commands: &mut Commands, player_list: &mut ResMut<PlayerList>, player_id: u32, color: Color) {
+
+    if let Some(player) = player_list.get_mut(&player_id){
+        player.snake_segments.clear();
+        player.snake_segments.push(commands
+            .spawn(SpriteBundle {
+                sprite: Sprite {
+                    color,
+                    ..default()
+                },
+                ..default()
+            })
+            .insert(SnakeHead {
+                direction: Direction::Up,
+            })
+            .insert(PlayerId::new(player_id))
+            .insert(SnakeSegment)
+            .insert(player.spawn_pos)
+            .insert(Size::square(0.8))
+            .id());
+            
+            player.snake_segments.push(spawn_segment(&mut commands, Position::new(player.spawn_pos.x(), player.spawn_pos.y() - 1)));
+    }
+}
+
+pub fn size_scaling(windows: Res<Windows>, config: Res<GameConfig>, mut q: Query<(&Size, &mut Transform)>) {
+    if let Some(window) = windows.get_primary(){
+        for (sprite_size, mut transform) in q.iter_mut() {
+            transform.scale = Vec3::new(
+                sprite_size.width / config.arena_width as f32 * window.width() as f32,
+                sprite_size.height / config.arena_height as f32 * window.height() as f32,
+                1.0,
+            );
+        }
+    }
+}
+
+pub fn position_translation(windows: Res<Windows>, config: Res<GameConfig>, mut q: Query<(&Position, &mut Transform)>) {
+    fn convert(pos: f32, bound_window: f32, bound_game: f32) -> f32 {
+        let tile_size = bound_window / bound_game;
+        pos / bound_game * bound_window - (bound_window / 2.) + (tile_size / 2.)
+    }
+    if let Some(window) = windows.get_primary(){
+        for (pos, mut transform) in q.iter_mut() {
+            transform.translation = Vec3::new(
+                convert(pos.x() as f32, window.width() as f32, config.arena_width as f32),
+                convert(pos.y() as f32, window.height() as f32, config.arena_height as f32),
+                0.0,
+            );
+        }
+    }
+}
+
+/// 增加蛇身
+pub fn spawn_segment(commands: &mut Commands, position: Position) -> Entity {
+    commands
+        .spawn(SpriteBundle {
+            sprite: Sprite {
+                color: SNAKE_SEGMENT_COLOR,
+                ..default()
+            },
+            ..default()
+        })
+        .insert(SnakeSegment)
+        .insert(position)
+        .insert(Size::square(0.65))
+        .id()
+}
+
+pub struct HeadlessPlugins;
+
+impl PluginGroup for HeadlessPlugins {
+    fn build(self) -> PluginGroupBuilder {
+        PluginGroupBuilder::start::<Self>()
+        .add(LogPlugin::default())
+        .add(CorePlugin::default())
+        .add(TimePlugin::default())
+        .add(ScheduleRunnerPlugin::default())
+    }
+}
@@ -0,0 +1,332 @@
+//! 控制蛇的"大脑"
+//!
+//! 每个移动周期开始前, 大脑观察场地([`WorldView`]), 为一条蛇选择下一步的方向。
+//! 服务器的NPC、训练程序和测试都通过[`SnakeBrain`]使用不同的策略, 可以互相对战。
+//!
+//! 内置的策略按难度从低到高:
+//! - [`RandomBrain`] 随机游走, 只避开紧挨着的障碍
+//! - [`GreedyBrain`] 朝最近的食物走
+//! - [`FloodFillBrain`] 选择可以活动的空间最大的方向, 空间一样大时朝食物走
+//! - [`AStarBrain`] 用A*寻找到食物的路径, 走过去以后空间不够时改用FloodFill
+//!
+//! NEAT神经网络的实现在npc中。
+
+use std::{cmp::Reverse, collections::{BinaryHeap, VecDeque}};
+
+use rand::Rng;
+
+use crate::{Direction, GameRng, GameState, Position};
+
+/// 所有方向
+pub const DIRECTIONS: [Direction; 4] = [Direction::Up, Direction::Down, Direction::Left, Direction::Right];
+
+/// 一个移动周期内场地的样子, 所有大脑共用, 避免每次判断障碍都遍历所有蛇
+pub struct WorldView<'a>{
+    pub world: &'a GameState,
+    width: i32,
+    height: i32,
+    /// 被蛇身占据的格子
+    occupied: Vec<bool>,
+    /// 有食物的格子
+    foods: Vec<bool>,
+}
+
+impl<'a> WorldView<'a>{
+    pub fn new(world: &'a GameState) -> Self{
+        let width = world.config.arena_width as i32;
+        let height = world.config.arena_height as i32;
+        let mut occupied = vec![false; (width * height) as usize];
+        for snake in world.snakes.values(){
+            for position in snake.body.iter().filter(|position| world.in_arena(**position)){
+                occupied[(position.y() * width + position.x()) as usize] = true;
+            }
+        }
+        let mut foods = vec![false; (width * height) as usize];
+        for food in world.foods.iter().filter(|food| world.in_arena(**food)){
+            foods[(food.y() * width + food.x()) as usize] = true;
+        }
+        Self{ world, width, height, occupied, foods }
+    }
+
+    pub fn width(&self) -> i32{
+        self.width
+    }
+
+    pub fn height(&self) -> i32{
+        self.height
+    }
+
+    /// 场地外或者有蛇身的格子
+    pub fn is_blocked(&self, position: Position) -> bool{
+        !self.world.in_arena(position) || self.occupied[self.index(position)]
+    }
+
+    /// 格子上是否有蛇身, 场地外返回false
+    pub fn has_body(&self, position: Position) -> bool{
+        self.world.in_arena(position) && self.occupied[self.index(position)]
+    }
+
+    /// 格子上是否有食物, 场地外返回false
+    pub fn has_food(&self, position: Position) -> bool{
+        self.world.in_arena(position) && self.foods[self.index(position)]
+    }
+
+    /// 玩家可以走的方向: 不掉头, 下一格没有障碍
+    pub fn safe_directions(&self, player_id: &str) -> Vec<Direction>{
+        let snake = match self.world.snakes.get(player_id){
+            Some(v) => v,
+            None => return vec![]
+        };
+        DIRECTIONS.iter()
+            .filter(|direction| **direction != snake.direction.opposite())
+            .filter(|direction| !self.is_blocked(snake.head().step(**direction)))
+            .copied()
+            .collect()
+    }
+
+    /// 离位置最近(曼哈顿距离)的食物
+    pub fn nearest_food(&self, position: Position) -> Option<Position>{
+        self.world.foods.iter().copied().min_by_key(|food| distance(*food, position))
+    }
+
+    /// 从某个格子出发能到达的空格子数量, 最多数到limit
+    pub fn reachable_area(&self, start: Position, limit: usize) -> usize{
+        if self.is_blocked(start){
+            return 0;
+        }
+        let mut visited = vec![false; self.occupied.len()];
+        let mut queue = VecDeque::from([start]);
+        visited[self.index(start)] = true;
+        let mut count = 0;
+        while let Some(position) = queue.pop_front(){
+            count += 1;
+            if count >= limit{
+                break;
+            }
+            for direction in DIRECTIONS{
+                let next = position.step(direction);
+                if !self.is_blocked(next) && !visited[self.index(next)]{
+                    visited[self.index(next)] = true;
+                    queue.push_back(next);
+                }
+            }
+        }
+        count
+    }
+
+    /// A*寻路, 返回第一步的方向
+    pub fn path_to(&self, start: Position, target: Position) -> Option<Direction>{
+        let mut open = BinaryHeap::new();
+        // 每个格子的(已走步数, 第一步的方向)
+        let mut visited: Vec<Option<(i32, Direction)>> = vec![None; self.occupied.len()];
+        for direction in DIRECTIONS{
+            let next = start.step(direction);
+            if !self.is_blocked(next){
+                visited[self.index(next)] = Some((1, direction));
+                open.push(Reverse((1 + distance(next, target), 1, next)));
+            }
+        }
+        while let Some(Reverse((_, cost, position))) = open.pop(){
+            let (best, first) = visited[self.index(position)]?;
+            // 同一个格子后来找到了更短的路径, 这是过期的记录
+            if best < cost{
+                continue;
+            }
+            if position == target{
+                return Some(first);
+            }
+            for direction in DIRECTIONS{
+                let next = position.step(direction);
+                if self.is_blocked(next) || visited[self.index(next)].map_or(false, |(other, _)| other <= cost + 1){
+                    continue;
+                }
+                visited[self.index(next)] = Some((cost + 1, first));
+                open.push(Reverse((cost + 1 + distance(next, target), cost + 1, next)));
+            }
+        }
+        None
+    }
+
+    fn index(&self, position: Position) -> usize{
+        (position.y() * self.width + position.x()) as usize
+    }
+}
+
+/// 曼哈顿距离
+pub fn distance(a: Position, b: Position) -> i32{
+    (a.x() - b.x()).abs() + (a.y() - b.y()).abs()
+}
+
+/// 为一条蛇选择方向的策略
+pub trait SnakeBrain: Send + Sync{
+    /// 返回下一步的方向, 返回None时保持原来的方向
+    fn think(&mut self, view: &WorldView, player_id: &str) -> Option<Direction>;
+}
+
+/// 随机游走: 大部分时间直走, 偶尔随机转向, 前方有障碍时转向安全的方向
+pub struct RandomBrain{
+    rng: GameRng,
+}
+
+impl RandomBrain{
+    /// 直走的概率
+    const KEEP_DIRECTION: f64 = 0.8;
+
+    pub fn new(seed: u64) -> Self{
+        Self{ rng: GameRng::new(seed) }
+    }
+}
+
+impl SnakeBrain for RandomBrain{
+    fn think(&mut self, view: &WorldView, player_id: &str) -> Option<Direction>{
+        let current = view.world.snakes.get(player_id)?.direction;
+        let safe = view.safe_directions(player_id);
+        if safe.contains(&current) && self.rng.gen_bool(Self::KEEP_DIRECTION){
+            return Some(current);
+        }
+        if safe.is_empty(){
+            return None;
+        }
+        Some(safe[self.rng.gen_range(0..safe.len())])
+    }
+}
+
+/// 贪心: 在安全的方向中选择离最近的食物最近的
+pub struct GreedyBrain;
+
+impl SnakeBrain for GreedyBrain{
+    fn think(&mut self, view: &WorldView, player_id: &str) -> Option<Direction>{
+        let head = view.world.snakes.get(player_id)?.head();
+        let food = view.nearest_food(head);
+        view.safe_directions(player_id).into_iter()
+            .min_by_key(|direction| food.map_or(0, |food| distance(head.step(*direction), food)))
+    }
+}
+
+/// 求生: 选择可以活动的空间最大的方向, 空间足够时朝食物走
+pub struct FloodFillBrain;
+
+impl FloodFillBrain{
+    /// 每个方向的可活动空间, 只需要数到比蛇身长一些
+    fn areas(view: &WorldView, player_id: &str) -> Vec<(Direction, usize)>{
+        let snake = match view.world.snakes.get(player_id){
+            Some(v) => v,
+            None => return vec![]
+        };
+        let limit = snake.body.len() * 2 + 1;
+        view.safe_directions(player_id).into_iter()
+            .map(|direction| (direction, view.reachable_area(snake.head().step(direction), limit)))
+            .collect()
+    }
+}
+
+impl SnakeBrain for FloodFillBrain{
+    fn think(&mut self, view: &WorldView, player_id: &str) -> Option<Direction>{
+        let head = view.world.snakes.get(player_id)?.head();
+        let food = view.nearest_food(head);
+        Self::areas(view, player_id).into_iter()
+            .max_by_key(|(direction, area)| (*area, Reverse(food.map_or(0, |food| distance(head.step(*direction), food)))))
+            .map(|(direction, _)| direction)
+    }
+}
+
+/// 寻路: 沿着A*找到的路径走向最近的食物; 没有路径或者那个方向空间不够时改用FloodFill
+pub struct AStarBrain;
+
+impl SnakeBrain for AStarBrain{
+    fn think(&mut self, view: &WorldView, player_id: &str) -> Option<Direction>{
+        let snake = view.world.snakes.get(player_id)?;
+        let head = snake.head();
+        let areas = FloodFillBrain::areas(view, player_id);
+        let enough = |direction: Direction| areas.iter()
+            .any(|(other, area)| *other == direction && *area > snake.body.len());
+        if let Some(direction) = view.nearest_food(head).and_then(|food| view.path_to(head, food)){
+            if direction != snake.direction.opposite() && enough(direction){
+                return Some(direction);
+            }
+        }
+        FloodFillBrain.think(view, player_id)
+    }
+}
+
+/// 按名字创建内置的大脑: random, greedy, floodfill, astar
+pub fn builtin_brain(name: &str, seed: u64) -> Option<Box<dyn SnakeBrain>>{
+    match name{
+        "random" => Some(Box::new(RandomBrain::new(seed))),
+        "greedy" => Some(Box::new(GreedyBrain)),
+        "floodfill" => Some(Box::new(FloodFillBrain)),
+        "astar" => Some(Box::new(AStarBrain)),
+        _ => None
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use std::collections::VecDeque;
+
+    use super::*;
+    use crate::{GameConfig, GameEvent, Snake};
+
+    fn state(width: u32, height: u32) -> GameState{
+        let config = GameConfig{ arena_width: width, arena_height: height, max_foods: 1, ..Default::default() };
+        GameState::new(config, GameRng::new(7))
+    }
+
+    /// 在场地上放一条蛇, body的第一个坐标是蛇头
+    fn place(state: &mut GameState, player_id: &str, body: &[(i32, i32)], direction: Direction){
+        state.snakes.insert(player_id.to_string(), Snake{
+            player_id: player_id.to_string(),
+            net_id: state.snakes.len() as u32 + 1,
+            player_name: player_id.to_string(),
+            direction,
+            turns: VecDeque::new(),
+            body: body.iter().map(|(x, y)| Position::new(*x, *y)).collect(),
+            last_tail_position: None,
+            paused: false,
+            kills: 0,
+        });
+    }
+
+    #[test]
+    fn astar_reaches_food_on_an_open_board(){
+        let mut state = state(10, 10);
+        place(&mut state, "a", &[(2, 2), (2, 1), (2, 0)], Direction::Up);
+        state.foods.push(Position::new(7, 6));
+        let mut brain = AStarBrain;
+        // 最短路径是9步
+        for _ in 0..9{
+            let direction = brain.think(&WorldView::new(&state), "a").expect("没有可以走的方向");
+            let events = state.step(&[("a".to_string(), direction)]);
+            if events.iter().any(|event| matches!(event, GameEvent::FoodEaten{ player_id, .. } if player_id == "a")){
+                return;
+            }
+        }
+        panic!("没有沿着最短路径吃到食物");
+    }
+
+    #[test]
+    fn floodfill_avoids_a_dead_end_pocket(){
+        let mut state = state(7, 7);
+        // b挡住了左下角, 往左走只剩3格
+        place(&mut state, "b", &[(2, 1), (1, 1), (0, 1)], Direction::Right);
+        place(&mut state, "a", &[(3, 0), (4, 0), (5, 0)], Direction::Left);
+        state.foods.push(Position::new(0, 0));
+        let view = WorldView::new(&state);
+        assert_eq!(GreedyBrain.think(&view, "a"), Some(Direction::Left));
+        assert_eq!(FloodFillBrain.think(&view, "a"), Some(Direction::Up));
+    }
+
+    #[test]
+    fn greedy_never_reverses(){
+        for direction in DIRECTIONS{
+            let mut state = state(9, 9);
+            place(&mut state, "a", &[(4, 4)], direction);
+            // 食物在正后方
+            let behind = Position::new(4, 4).step(direction.opposite()).step(direction.opposite());
+            state.foods.push(behind);
+            let choice = GreedyBrain.think(&WorldView::new(&state), "a");
+            assert!(choice.is_some());
+            assert_ne!(choice, Some(direction.opposite()));
+        }
+    }
+}
@@ -0,0 +1,102 @@
+use anyhow::{anyhow, Result};
+use serde::{Serialize, Deserialize};
+
+use crate::{ARENA_HEIGHT, ARENA_WIDTH};
+
+/// 游戏设置, 服务器启动时读取, 客户端连接后由服务器下发
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+#[cfg_attr(feature = "bevy", derive(bevy::prelude::Resource))]
+#[serde(default)]
+pub struct GameConfig{
+    /// 网格宽度
+    pub arena_width: u32,
+    /// 网格高度
+    pub arena_height: u32,
+    /// 移动周期(秒)
+    pub tick_interval: f32,
+    /// 食物生成间隔(秒)
+    pub food_spawn_interval: f32,
+    /// 最多生成的食物数量
+    pub max_foods: usize,
+    /// 蛇的初始长度
+    pub start_length: usize,
+    /// 最多玩家数量
+    pub max_players: usize,
+}
+
+impl Default for GameConfig{
+    fn default() -> Self {
+        Self{
+            arena_width: ARENA_WIDTH,
+            arena_height: ARENA_HEIGHT,
+            tick_interval: 0.15,
+            food_spawn_interval: 2.0,
+            max_foods: 20,
+            start_length: 2,
+            max_players: 32,
+        }
+    }
+}
+
+impl GameConfig{
+    /// 每隔多少个移动周期生成一个食物
+    pub fn food_spawn_ticks(&self) -> u64{
+        ((self.food_spawn_interval / self.tick_interval).round() as u64).max(1)
+    }
+
+    pub fn validate(&self) -> Result<()>{
+        if self.arena_width == 0 || self.arena_height == 0{
+            return Err(anyhow!("网格大小不能为0"));
+        }
+        if !is_positive(self.tick_interval) || !is_positive(self.food_spawn_interval){
+            return Err(anyhow!("移动周期和食物生成间隔必须大于0"));
+        }
+        if self.start_length == 0 || self.start_length > self.arena_height as usize{
+            return Err(anyhow!("蛇的初始长度必须在1到{}之间", self.arena_height));
+        }
+        if self.max_foods == 0{
+            return Err(anyhow!("最多生成的食物数量不能为0"));
+        }
+        Ok(())
+    }
+}
+
+/// 大于0的有限数, NaN和无穷大都不合法
+fn is_positive(value: f32) -> bool{
+    value.is_finite() && value > 0.0
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn default_is_valid(){
+        assert!(GameConfig::default().validate().is_ok());
+    }
+
+    #[test]
+    fn zero_size_arena_is_rejected(){
+        assert!(GameConfig{ arena_width: 0, ..Default::default() }.validate().is_err());
+        assert!(GameConfig{ arena_height: 0, ..Default::default() }.validate().is_err());
+    }
+
+    #[test]
+    fn non_positive_tick_interval_is_rejected(){
+        for tick_interval in [0., -0.15, f32::NAN]{
+            assert!(GameConfig{ tick_interval, ..Default::default() }.validate().is_err());
+        }
+    }
+
+    #[test]
+    fn start_length_must_fit_in_the_arena(){
+        let config = GameConfig{ arena_height: 5, start_length: 6, ..Default::default() };
+        assert!(config.validate().is_err());
+        assert!(GameConfig{ start_length: 5, ..config }.validate().is_ok());
+    }
+
+    #[test]
+    fn zero_max_foods_is_rejected(){
+        assert!(GameConfig{ max_foods: 0, ..Default::default() }.validate().is_err());
+    }
+}
@@ -0,0 +1,619 @@
+//! 不依赖Bevy的游戏规则
+//!
+//! 服务器的每个房间、录像重放和NPC训练程序都直接使用[`GameState`]推进游戏。
+
+use std::collections::{BTreeMap, VecDeque};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use anyhow::{anyhow, Result};
+
+use crate::{Direction, GameConfig, GameRng, LeaderBoard, Position};
+
+/// 得分榜最多显示的玩家数量
+pub const LEADER_BOARD_SIZE: usize = 10;
+/// 每个玩家最多缓存的转向输入
+pub const TURN_QUEUE_SIZE: usize = 3;
+
+/// 一条蛇
+#[derive(Debug, Clone)]
+pub struct Snake {
+    pub player_id: String,
+    /// 同步给客户端时使用的数字id
+    pub net_id: u32,
+    pub player_name: String,
+    /// 上一个移动周期实际移动的方向
+    pub direction: Direction,
+    /// 还没有执行的转向, 每个移动周期最多执行一个
+    pub turns: VecDeque<Direction>,
+    /// 蛇身坐标, 第一个是蛇头
+    pub body: Vec<Position>,
+    /// 上一次移动前蛇尾的位置, 长大时新的蛇身放在这里
+    pub last_tail_position: Option<Position>,
+    /// 玩家掉线, 蛇停在原地等待重连
+    pub paused: bool,
+    /// 这条蛇撞死的其他蛇的数量
+    pub kills: u32,
+}
+
+impl Snake {
+    pub fn head(&self) -> Position {
+        self.body[0]
+    }
+
+    /// 缓存一个转向输入, 队列已满时丢弃
+    pub fn queue_turn(&mut self, direction: Direction) {
+        if self.turns.len() < TURN_QUEUE_SIZE {
+            self.turns.push_back(direction);
+        }
+    }
+
+    /// 取出下一个有效的转向并改变方向
+    ///
+    /// 和上一个周期移动方向相同或者相反(掉头)的输入没有意义, 直接丢弃。
+    fn next_turn(&mut self) {
+        while let Some(direction) = self.turns.pop_front() {
+            if direction != self.direction && direction != self.direction.opposite() {
+                self.direction = direction;
+                return;
+            }
+        }
+    }
+}
+
+/// 死亡原因
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DeathCause {
+    /// 撞墙
+    Wall,
+    /// 撞到自己的蛇身
+    SelfCollision,
+    /// 撞到其他蛇的蛇身
+    Collision,
+    /// 和其他蛇头对头相撞(包括互相穿过)
+    HeadOn,
+}
+
+/// 一条蛇的死亡信息
+#[derive(Debug, Clone, PartialEq)]
+pub struct Death {
+    pub player_id: String,
+    pub player_name: String,
+    /// 被撞到的蛇的玩家id, 撞墙和撞到自己时为None
+    pub killer: Option<String>,
+    pub killer_name: Option<String>,
+    /// 算上这一次, 凶手一共撞死的蛇的数量, 凶手同时死亡时不计
+    pub killer_kills: u32,
+    pub cause: DeathCause,
+    /// 死亡时的长度
+    pub final_length: usize,
+}
+
+/// 一次step中发生的事件
+#[derive(Debug, Clone, PartialEq)]
+pub enum GameEvent {
+    /// 所有蛇都移动了一格
+    SnakesMoved,
+    /// 玩家吃到了食物
+    FoodEaten { player_id: String, position: Position },
+    /// 玩家的蛇长大了
+    SnakeGrew { player_id: String },
+    /// 玩家死亡, 蛇已经从游戏中删除, 需要重新加入游戏
+    PlayerDied(Death),
+    /// 玩家重生
+    PlayerSpawned { player_id: String },
+    /// 生成了一个食物
+    FoodSpawned(Position),
+    /// 得分榜有变化
+    LeaderBoardChanged,
+}
+
+/// 游戏世界: 所有蛇、食物和得分榜
+#[derive(Debug, Clone)]
+pub struct GameState {
+    pub config: GameConfig,
+    /// 玩家的蛇, 按玩家id排序保证每次遍历顺序一致
+    pub snakes: BTreeMap<String, Snake>,
+    pub foods: Vec<Position>,
+    pub leader_board: LeaderBoard,
+    /// 已经执行的step次数
+    pub tick: u64,
+    /// 出生点和食物位置都从这里取随机数
+    pub rng: GameRng,
+    /// 下一个加入的玩家使用的数字id
+    pub next_net_id: u32,
+}
+
+impl Default for GameState {
+    fn default() -> Self {
+        Self::new(GameConfig::default(), GameRng::from_entropy())
+    }
+}
+
+impl GameState {
+    pub fn new(config: GameConfig, rng: GameRng) -> Self {
+        Self {
+            config,
+            snakes: BTreeMap::new(),
+            foods: vec![],
+            leader_board: LeaderBoard::default(),
+            tick: 0,
+            rng,
+            next_net_id: 1,
+        }
+    }
+
+    /// 添加玩家并生成它的蛇
+    ///
+    /// 玩家已经有蛇、玩家数量已满或者找不到空位时返回错误
+    pub fn add_player(&mut self, player_id: String, player_name: String) -> Result<Vec<GameEvent>> {
+        if self.snakes.contains_key(&player_id) {
+            return Err(anyhow!("玩家已经在游戏中"));
+        }
+        if self.snakes.len() >= self.config.max_players {
+            return Err(anyhow!("玩家数量已满({})", self.config.max_players));
+        }
+        let body = self.spawn_body().ok_or_else(|| anyhow!("场地上没有空位生成蛇"))?;
+        let net_id = self.next_net_id;
+        self.next_net_id += 1;
+        self.snakes.insert(player_id.clone(), Snake {
+            player_id: player_id.clone(),
+            net_id,
+            player_name,
+            direction: Direction::Up,
+            turns: VecDeque::new(),
+            body,
+            last_tail_position: None,
+            paused: false,
+            kills: 0,
+        });
+        Ok(vec![GameEvent::PlayerSpawned { player_id }, GameEvent::LeaderBoardChanged])
+    }
+
+    /// 删除玩家(掉线)
+    pub fn remove_player(&mut self, player_id: &str) -> Option<Snake> {
+        self.snakes.remove(player_id)
+    }
+
+    /// 暂停或者恢复玩家的蛇, 暂停的蛇不移动、不会死亡, 但仍然是障碍
+    pub fn set_paused(&mut self, player_id: &str, paused: bool) {
+        if let Some(snake) = self.snakes.get_mut(player_id) {
+            snake.paused = paused;
+            snake.turns.clear();
+        }
+    }
+
+    /// 推进一个移动周期
+    ///
+    /// inputs是这个周期内收到的玩家方向输入, 按收到的顺序放入每个玩家的转向队列,
+    /// 每条蛇每个周期只执行队列中的一个转向, 一个周期内连续按下的两个方向会在两个周期内依次执行。
+    pub fn step(&mut self, inputs: &[(String, Direction)]) -> Vec<GameEvent> {
+        let mut events = vec![];
+        self.tick += 1;
+
+        for (player_id, direction) in inputs {
+            if let Some(snake) = self.snakes.get_mut(player_id).filter(|snake| !snake.paused) {
+                snake.queue_turn(*direction);
+            }
+        }
+        for snake in self.snakes.values_mut().filter(|snake| !snake.paused) {
+            snake.next_turn();
+        }
+
+        let mut dead = self.move_snakes();
+        events.push(GameEvent::SnakesMoved);
+
+        self.eat_foods(&dead, &mut events);
+
+        // 撞到其他蛇时算作对方的击杀, 对方同时死亡时不计
+        let dead_ids = dead.iter().map(|death| death.player_id.clone()).collect::<Vec<String>>();
+        for death in dead.iter_mut() {
+            let killer = match &death.killer {
+                Some(killer) if !dead_ids.contains(killer) => killer.clone(),
+                _ => continue,
+            };
+            if let Some(snake) = self.snakes.get_mut(&killer) {
+                snake.kills += 1;
+                death.killer_kills = snake.kills;
+            }
+        }
+
+        // 死亡的蛇删除, 玩家自己决定什么时候重新加入
+        for death in dead {
+            self.snakes.remove(&death.player_id);
+            events.push(GameEvent::PlayerDied(death));
+        }
+
+        if self.tick % self.config.food_spawn_ticks() == 0 {
+            if let Some(position) = self.spawn_food() {
+                events.push(GameEvent::FoodSpawned(position));
+            }
+        }
+
+        events
+    }
+
+    /// 移动所有蛇, 返回死亡的玩家和死亡原因
+    ///
+    /// 先计算出所有蛇的下一个蛇头位置, 再根据移动前的状态统一判定碰撞, 结果和蛇的遍历顺序无关:
+    /// - 撞墙: 蛇头移出场地, 死亡
+    /// - 头对头: 两条以上的蛇头移动到同一格, 全部死亡
+    /// - 交换位置: 两条蛇的蛇头移动到对方蛇头所在的格子(互相穿过), 都死亡
+    /// - 撞到蛇身: 蛇头移动到任何一条蛇(包括自己)移动后的蛇身上, 死亡;
+    ///   其他蛇移动前的蛇头在移动后就是蛇身, 所以撞到对方蛇头所在的格子也会死亡
+    /// - 追尾: 没有长大的蛇, 蛇尾这一步会离开, 可以移动到这个格子上(包括自己的蛇尾);
+    ///   这一步吃到食物的蛇蛇尾不会离开, 仍然是障碍
+    ///
+    /// 死亡的蛇这一步仍然算作障碍, 暂停的蛇不移动, 整条蛇都是障碍。
+    /// 同时满足多个条件时, 按上面的顺序取第一个作为死亡原因。
+    fn move_snakes(&mut self) -> Vec<Death> {
+        // 所有移动的蛇的下一个蛇头
+        let next_heads = self.snakes.iter()
+            .filter(|(_, snake)| !snake.paused)
+            .map(|(id, snake)| (id.clone(), snake.head().step(snake.direction)))
+            .collect::<BTreeMap<String, Position>>();
+
+        // 移动后仍然被蛇身占据的格子, 以及占据它的蛇
+        let mut bodies = BTreeMap::new();
+        for (id, snake) in self.snakes.iter() {
+            let keep = match next_heads.get(id) {
+                None => snake.body.len(),
+                Some(next_head) if self.foods.contains(next_head) => snake.body.len(),
+                Some(_) => snake.body.len() - 1,
+            };
+            bodies.extend(snake.body[..keep].iter().map(|position| (*position, id)));
+        }
+
+        let mut dead = vec![];
+        for (id, snake) in self.snakes.iter().filter(|(_, snake)| !snake.paused) {
+            let next_head = next_heads[id];
+            let head_on = next_heads.iter()
+                .find(|(other_id, other_next)| *other_id != id && **other_next == next_head)
+                .map(|(other_id, _)| other_id);
+            let swapped = next_heads.iter()
+                .find(|(other_id, other_next)| *other_id != id
+                    && **other_next == snake.head()
+                    && self.snakes[*other_id].head() == next_head)
+                .map(|(other_id, _)| other_id);
+
+            let (cause, killer) = if !self.in_arena(next_head) {
+                (DeathCause::Wall, None)
+            } else if let Some(other_id) = head_on.or(swapped) {
+                (DeathCause::HeadOn, Some(other_id.clone()))
+            } else if let Some(owner) = bodies.get(&next_head) {
+                if *owner == id {
+                    (DeathCause::SelfCollision, None)
+                } else {
+                    (DeathCause::Collision, Some((*owner).clone()))
+                }
+            } else {
+                continue;
+            };
+            dead.push(Death {
+                player_id: id.clone(),
+                player_name: snake.player_name.clone(),
+                killer_name: killer.as_ref().map(|killer| self.snakes[killer].player_name.clone()),
+                killer,
+                killer_kills: 0,
+                cause,
+                final_length: snake.body.len(),
+            });
+        }
+
+        for (id, snake) in self.snakes.iter_mut().filter(|(_, snake)| !snake.paused) {
+            // 所有蛇身(不包括蛇头)跟随前一个蛇身(包括蛇头)的位置
+            snake.last_tail_position = snake.body.last().copied();
+            snake.body.pop();
+            snake.body.insert(0, next_heads[id]);
+        }
+
+        dead
+    }
+
+    /// 检测蛇头是否吃到了食物, 吃到以后长大并更新得分榜, 死亡的蛇不会吃食物
+    fn eat_foods(&mut self, dead: &[Death], events: &mut Vec<GameEvent>) {
+        let mut grown = false;
+        for snake in self.snakes.values_mut() {
+            if dead.iter().any(|death| death.player_id == snake.player_id) || snake.paused {
+                continue;
+            }
+            let head = snake.head();
+            if let Some(idx) = self.foods.iter().position(|food| *food == head) {
+                self.foods.remove(idx);
+                events.push(GameEvent::FoodEaten { player_id: snake.player_id.clone(), position: head });
+
+                if let Some(last_tail_position) = snake.last_tail_position {
+                    snake.body.push(last_tail_position);
+                }
+                events.push(GameEvent::SnakeGrew { player_id: snake.player_id.clone() });
+                update_leader_board(&mut self.leader_board, &snake.player_name, snake.body.len());
+                grown = true;
+            }
+        }
+        if grown {
+            events.push(GameEvent::LeaderBoardChanged);
+        }
+    }
+
+    /// 在场地底部随机选一列生成蛇, 蛇头朝上
+    ///
+    /// 蛇身经过的格子都不能有其他蛇, 随机几次都找不到这样的列时返回None
+    fn spawn_body(&mut self) -> Option<Vec<Position>> {
+        for _ in 0..self.config.arena_width {
+            let x = self.rng.gen_range(0..self.config.arena_width as i32);
+            let body = (0..self.config.start_length as i32).rev().map(|y| Position::new(x, y)).collect::<Vec<Position>>();
+            if !body.iter().any(|position| self.is_occupied(*position)) {
+                return Some(body);
+            }
+        }
+        None
+    }
+
+    /// 生成一个食物, 食物不会生成在蛇身上, 场地太满时这次不生成
+    pub fn spawn_food(&mut self) -> Option<Position> {
+        // 食物数量有上限
+        if self.foods.len() >= self.config.max_foods {
+            return None;
+        }
+
+        let cells = self.config.arena_width * self.config.arena_height;
+        for _ in 0..cells {
+            let x = self.rng.gen_range(0..self.config.arena_width as i32);
+            let y = self.rng.gen_range(0..self.config.arena_height as i32);
+            let position = Position::new(x, y);
+            //食物位置在蛇身上，重新生成
+            if !self.is_occupied(position) {
+                self.foods.push(position);
+                return Some(position);
+            }
+        }
+        None
+    }
+
+    pub fn in_arena(&self, position: Position) -> bool {
+        position.x >= 0
+            && position.y >= 0
+            && (position.x as u32) < self.config.arena_width
+            && (position.y as u32) < self.config.arena_height
+    }
+
+    /// 位置上是否有蛇
+    pub fn is_occupied(&self, position: Position) -> bool {
+        self.snakes.values().any(|snake| snake.body.contains(&position))
+    }
+}
+
+/// 更新玩家的最高得分, 只保留前LEADER_BOARD_SIZE名
+fn update_leader_board(leader_board: &mut LeaderBoard, player_name: &str, score: usize) {
+    match leader_board.iter_mut().find(|(name, _)| name == player_name) {
+        Some((_, best)) => {
+            if score > *best {
+                *best = score;
+            }
+        }
+        None => leader_board.push((player_name.to_string(), score)),
+    }
+    // 排序
+    leader_board.sort_by(|(_, score1), (_, score2)| score2.cmp(score1));
+    leader_board.truncate(LEADER_BOARD_SIZE);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state(width: u32, height: u32) -> GameState {
+        let config = GameConfig { arena_width: width, arena_height: height, start_length: 3, ..Default::default() };
+        GameState::new(config, GameRng::new(7))
+    }
+
+    /// 在场地上放一条蛇, body的第一个坐标是蛇头
+    fn place(state: &mut GameState, player_id: &str, body: &[(i32, i32)], direction: Direction) {
+        let net_id = state.next_net_id;
+        state.next_net_id += 1;
+        state.snakes.insert(player_id.to_string(), Snake {
+            player_id: player_id.to_string(),
+            net_id,
+            player_name: player_id.to_string(),
+            direction,
+            turns: VecDeque::new(),
+            body: body.iter().map(|(x, y)| Position::new(*x, *y)).collect(),
+            last_tail_position: None,
+            paused: false,
+            kills: 0,
+        });
+    }
+
+    /// 推进一个移动周期, 返回按玩家id排序的(死亡的玩家, 死亡原因, 凶手)
+    fn deaths(state: &mut GameState) -> Vec<(String, DeathCause, Option<String>)> {
+        let mut deaths = state.step(&[]).into_iter()
+            .filter_map(|event| match event {
+                GameEvent::PlayerDied(death) => Some((death.player_id, death.cause, death.killer)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        deaths.sort_by(|a, b| a.0.cmp(&b.0));
+        deaths
+    }
+
+    fn killed(player_id: &str, cause: DeathCause, killer: &str) -> (String, DeathCause, Option<String>) {
+        (player_id.to_string(), cause, Some(killer.to_string()))
+    }
+
+    #[test]
+    fn head_on_into_the_same_cell_kills_both() {
+        let mut state = state(10, 10);
+        place(&mut state, "a", &[(1, 5), (0, 5)], Direction::Right);
+        place(&mut state, "b", &[(3, 5), (4, 5)], Direction::Left);
+        assert_eq!(deaths(&mut state), vec![
+            killed("a", DeathCause::HeadOn, "b"),
+            killed("b", DeathCause::HeadOn, "a"),
+        ]);
+        assert!(state.snakes.is_empty());
+    }
+
+    #[test]
+    fn swapping_heads_kills_both() {
+        let mut state = state(10, 10);
+        place(&mut state, "a", &[(1, 5), (0, 5)], Direction::Right);
+        place(&mut state, "b", &[(2, 5), (3, 5)], Direction::Left);
+        assert_eq!(deaths(&mut state), vec![
+            killed("a", DeathCause::HeadOn, "b"),
+            killed("b", DeathCause::HeadOn, "a"),
+        ]);
+    }
+
+    #[test]
+    fn moving_into_another_head_cell_is_a_collision() {
+        let mut state = state(10, 10);
+        place(&mut state, "a", &[(1, 5), (0, 5)], Direction::Right);
+        place(&mut state, "b", &[(2, 5), (2, 4)], Direction::Up);
+        assert_eq!(deaths(&mut state), vec![killed("a", DeathCause::Collision, "b")]);
+        assert_eq!(state.snakes["b"].kills, 1);
+    }
+
+    #[test]
+    fn moving_into_another_body_is_a_collision() {
+        let mut state = state(10, 10);
+        place(&mut state, "a", &[(1, 5), (0, 5)], Direction::Right);
+        place(&mut state, "b", &[(2, 6), (2, 5), (2, 4)], Direction::Up);
+        assert_eq!(deaths(&mut state), vec![killed("a", DeathCause::Collision, "b")]);
+    }
+
+    #[test]
+    fn following_a_tail_that_leaves_is_safe() {
+        let mut state = state(10, 10);
+        // 绕成一圈的蛇, 蛇头移动到自己的蛇尾
+        place(&mut state, "a", &[(1, 1), (1, 2), (2, 2), (2, 1)], Direction::Right);
+        // c跟着b的蛇尾
+        place(&mut state, "b", &[(3, 5), (2, 5)], Direction::Right);
+        place(&mut state, "c", &[(1, 5), (0, 5)], Direction::Right);
+        assert_eq!(deaths(&mut state), vec![]);
+        assert_eq!(state.snakes["a"].head(), Position::new(2, 1));
+        assert_eq!(state.snakes["c"].head(), Position::new(2, 5));
+    }
+
+    #[test]
+    fn following_a_tail_whose_owner_eats_is_a_collision() {
+        let mut state = state(10, 10);
+        place(&mut state, "b", &[(3, 5), (2, 5)], Direction::Right);
+        place(&mut state, "c", &[(1, 5), (0, 5)], Direction::Right);
+        state.foods.push(Position::new(4, 5));
+        assert_eq!(deaths(&mut state), vec![killed("c", DeathCause::Collision, "b")]);
+        assert_eq!(state.snakes["b"].body.len(), 3);
+    }
+
+    #[test]
+    fn paused_snake_blocks_and_survives() {
+        let mut state = state(10, 10);
+        place(&mut state, "a", &[(1, 5), (0, 5)], Direction::Right);
+        place(&mut state, "b", &[(2, 5), (2, 4)], Direction::Left);
+        state.set_paused("b", true);
+        assert_eq!(deaths(&mut state), vec![killed("a", DeathCause::Collision, "b")]);
+        let b = &state.snakes["b"];
+        assert_eq!(b.body, vec![Position::new(2, 5), Position::new(2, 4)]);
+    }
+
+    /// 玩家a在一个周期内依次按下的方向
+    fn turns(directions: &[Direction]) -> Vec<(String, Direction)> {
+        directions.iter().map(|direction| ("a".to_string(), *direction)).collect()
+    }
+
+    #[test]
+    fn two_turns_in_one_tick_apply_over_two_ticks() {
+        let mut state = state(10, 10);
+        place(&mut state, "a", &[(3, 5), (2, 5)], Direction::Right);
+        state.step(&turns(&[Direction::Up, Direction::Left]));
+        assert_eq!(state.snakes["a"].head(), Position::new(3, 6));
+        state.step(&[]);
+        assert_eq!(state.snakes["a"].head(), Position::new(2, 6));
+        assert_eq!(state.snakes["a"].direction, Direction::Left);
+    }
+
+    #[test]
+    fn queued_reversal_is_checked_against_the_travelled_direction() {
+        let mut state = state(10, 10);
+        place(&mut state, "a", &[(3, 5), (2, 5)], Direction::Right);
+        // 执行Up以后Down就是掉头, 被丢弃
+        state.step(&turns(&[Direction::Up, Direction::Down]));
+        state.step(&[]);
+        assert_eq!(state.snakes["a"].head(), Position::new(3, 7));
+        assert_eq!(state.snakes["a"].direction, Direction::Up);
+        // 掉头的输入被丢弃后, 同一个周期内执行后面的转向
+        state.step(&turns(&[Direction::Down, Direction::Right]));
+        assert_eq!(state.snakes["a"].head(), Position::new(4, 7));
+    }
+
+    #[test]
+    fn same_direction_turns_are_dropped() {
+        let mut state = state(10, 10);
+        place(&mut state, "a", &[(3, 5), (2, 5)], Direction::Right);
+        state.step(&turns(&[Direction::Right, Direction::Up]));
+        assert_eq!(state.snakes["a"].head(), Position::new(3, 6));
+        assert!(state.snakes["a"].turns.is_empty());
+    }
+
+    #[test]
+    fn turn_queue_is_capped() {
+        let mut state = state(10, 10);
+        place(&mut state, "a", &[(3, 5), (2, 5)], Direction::Right);
+        // 第4个转向超出队列长度, 被丢弃
+        state.step(&turns(&[Direction::Up, Direction::Left, Direction::Up, Direction::Left]));
+        assert_eq!(state.snakes["a"].turns.len(), TURN_QUEUE_SIZE - 1);
+        state.step(&[]);
+        state.step(&[]);
+        state.step(&[]);
+        assert_eq!(state.snakes["a"].head(), Position::new(2, 8));
+    }
+
+    #[test]
+    fn result_does_not_depend_on_player_id_order() {
+        // 三个角色: follower跟着runner的蛇尾, runner撞到blocker的蛇身, blocker向上走
+        let outcome = |follower: &str, runner: &str, blocker: &str| {
+            let mut state = state(10, 10);
+            place(&mut state, follower, &[(2, 4), (1, 4)], Direction::Right);
+            place(&mut state, runner, &[(4, 4), (3, 4)], Direction::Right);
+            place(&mut state, blocker, &[(5, 5), (5, 4), (5, 3)], Direction::Up);
+            let deaths = deaths(&mut state);
+            let heads = [follower, runner, blocker].map(|id| state.snakes.get(id).map(|snake| snake.head()));
+            (deaths, heads, state.snakes.get(blocker).map(|snake| snake.kills))
+        };
+        let ids = ["a", "b", "c"];
+        for (f, r, b) in [(0, 1, 2), (0, 2, 1), (1, 0, 2), (1, 2, 0), (2, 0, 1), (2, 1, 0)] {
+            let (deaths, heads, kills) = outcome(ids[f], ids[r], ids[b]);
+            assert_eq!(deaths, vec![killed(ids[r], DeathCause::Collision, ids[b])]);
+            assert_eq!(heads, [Some(Position::new(3, 4)), None, Some(Position::new(5, 6))]);
+            assert_eq!(kills, Some(1));
+        }
+    }
+
+    #[test]
+    fn spawned_snakes_do_not_overlap() {
+        let mut state = state(4, 10);
+        let joined = (0..10).filter(|idx| state.add_player(format!("p{idx}"), format!("p{idx}")).is_ok()).count();
+        assert!(joined > 0 && joined <= 4);
+        let mut cells = state.snakes.values().flat_map(|snake| snake.body.iter().copied()).collect::<Vec<Position>>();
+        let total = cells.len();
+        cells.sort();
+        cells.dedup();
+        assert_eq!(cells.len(), total);
+    }
+
+    #[test]
+    fn add_player_fails_without_a_free_column() {
+        let mut state = state(1, 10);
+        assert!(state.add_player("a".to_string(), "a".to_string()).is_ok());
+        assert!(state.add_player("b".to_string(), "b".to_string()).is_err());
+        assert_eq!(state.snakes.len(), 1);
+    }
+
+    #[test]
+    fn add_player_keeps_an_existing_snake() {
+        let mut state = state(10, 10);
+        state.add_player("a".to_string(), "a".to_string()).unwrap();
+        let snake = state.snakes["a"].clone();
+        assert!(state.add_player("a".to_string(), "a".to_string()).is_err());
+        assert_eq!(state.snakes["a"].net_id, snake.net_id);
+        assert_eq!(state.snakes["a"].body, snake.body);
+    }
+}
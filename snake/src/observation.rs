@@ -0,0 +1,173 @@
+//! 智能体的观察
+//!
+//! NEAT训练程序、服务器上的NPC和外部的机器学习工具使用同一个观察定义,
+//! 训练出来的模型在哪里运行看到的都是一样的输入。
+//!
+//! 特征向量([`OBSERVATION_SIZE`]个数):
+//! - 从蛇头出发的8条射线(上、右上、右、右下、下、左下、左、左上), 每条射线3个值:
+//!   到墙、到蛇身、到食物的距离的倒数, 射线碰到墙之前没有蛇身或者食物时为0
+//! - 当前方向, 按上、下、左、右的顺序one-hot编码
+//! - 长度除以场地格子数
+//!
+//! 局部网格([`observe_grid`]): 以蛇头为中心的正方形区域, 每个格子一个值, 按行从下到上、从左到右排列。
+
+use crate::{GameState, Position, WorldView, DIRECTIONS};
+
+/// 射线方向(x, y), 从上开始顺时针
+pub const RAY_DIRECTIONS: [(i32, i32); 8] = [(0, 1), (1, 1), (1, 0), (1, -1), (0, -1), (-1, -1), (-1, 0), (-1, 1)];
+/// 特征向量的长度
+pub const OBSERVATION_SIZE: usize = RAY_DIRECTIONS.len() * 3 + DIRECTIONS.len() + 1;
+
+/// 局部网格中各种格子的值
+pub const GRID_EMPTY: f32 = 0.;
+pub const GRID_FOOD: f32 = 1.;
+pub const GRID_WALL: f32 = -1.;
+/// 自己的蛇身
+pub const GRID_SELF: f32 = -0.5;
+/// 其他蛇
+pub const GRID_OTHER: f32 = -0.75;
+
+/// 观察一条蛇, 玩家不在游戏中时返回None
+pub fn observe(world: &GameState, player_id: &str) -> Option<[f32; OBSERVATION_SIZE]>{
+    WorldView::new(world).observe(player_id)
+}
+
+/// 以蛇头为中心、边长 2*radius+1 的局部网格, 玩家不在游戏中时返回None
+pub fn observe_grid(world: &GameState, player_id: &str, radius: i32) -> Option<Vec<f32>>{
+    WorldView::new(world).observe_grid(player_id, radius)
+}
+
+impl<'a> WorldView<'a>{
+    /// 同[`observe`], 一个移动周期内观察多条蛇时共用同一个WorldView
+    pub fn observe(&self, player_id: &str) -> Option<[f32; OBSERVATION_SIZE]>{
+        let snake = self.world.snakes.get(player_id)?;
+        let head = snake.head();
+        let mut features = [0.; OBSERVATION_SIZE];
+
+        for (idx, (dx, dy)) in RAY_DIRECTIONS.iter().enumerate(){
+            let mut body = None;
+            let mut food = None;
+            let mut distance = 1;
+            let mut position = Position::new(head.x() + dx, head.y() + dy);
+            while self.world.in_arena(position){
+                if body.is_none() && self.has_body(position){
+                    body = Some(distance);
+                }
+                if food.is_none() && self.has_food(position){
+                    food = Some(distance);
+                }
+                position = Position::new(position.x() + dx, position.y() + dy);
+                distance += 1;
+            }
+            features[idx * 3] = 1. / distance as f32;
+            features[idx * 3 + 1] = body.map_or(0., |distance| 1. / distance as f32);
+            features[idx * 3 + 2] = food.map_or(0., |distance| 1. / distance as f32);
+        }
+
+        let offset = RAY_DIRECTIONS.len() * 3;
+        if let Some(idx) = DIRECTIONS.iter().position(|direction| *direction == snake.direction){
+            features[offset + idx] = 1.;
+        }
+        features[offset + DIRECTIONS.len()] = snake.body.len() as f32 / (self.width() * self.height()) as f32;
+        Some(features)
+    }
+
+    /// 同[`observe_grid`]
+    pub fn observe_grid(&self, player_id: &str, radius: i32) -> Option<Vec<f32>>{
+        let head = self.world.snakes.get(player_id)?.head();
+        let size = 2 * radius + 1;
+        let (left, bottom) = (head.x() - radius, head.y() - radius);
+        let mut grid = vec![GRID_EMPTY; (size * size) as usize];
+        for y in 0..size{
+            for x in 0..size{
+                let position = Position::new(left + x, bottom + y);
+                if !self.world.in_arena(position){
+                    grid[(y * size + x) as usize] = GRID_WALL;
+                }else if self.has_food(position){
+                    grid[(y * size + x) as usize] = GRID_FOOD;
+                }
+            }
+        }
+        // 只有蛇身需要知道是谁的
+        for (id, snake) in self.world.snakes.iter(){
+            let value = if id == player_id { GRID_SELF } else { GRID_OTHER };
+            for position in snake.body.iter(){
+                let (x, y) = (position.x() - left, position.y() - bottom);
+                if (0..size).contains(&x) && (0..size).contains(&y){
+                    grid[(y * size + x) as usize] = value;
+                }
+            }
+        }
+        Some(grid)
+    }
+}
+
+
+#[cfg(test)]
+mod tests{
+    use std::collections::VecDeque;
+
+    use super::*;
+    use crate::{Direction, GameConfig, GameRng, Snake};
+
+    fn state(width: u32, height: u32) -> GameState{
+        let config = GameConfig{ arena_width: width, arena_height: height, ..Default::default() };
+        GameState::new(config, GameRng::new(7))
+    }
+
+    /// 在场地上放一条蛇, body的第一个坐标是蛇头
+    fn place(state: &mut GameState, player_id: &str, body: &[(i32, i32)], direction: Direction){
+        state.snakes.insert(player_id.to_string(), Snake{
+            player_id: player_id.to_string(),
+            net_id: state.snakes.len() as u32 + 1,
+            player_name: player_id.to_string(),
+            direction,
+            turns: VecDeque::new(),
+            body: body.iter().map(|(x, y)| Position::new(*x, *y)).collect(),
+            last_tail_position: None,
+            paused: false,
+            kills: 0,
+        });
+    }
+
+    #[test]
+    fn observation_has_a_fixed_size(){
+        assert_eq!(OBSERVATION_SIZE, 29);
+        let mut state = state(5, 5);
+        place(&mut state, "a", &[(1, 2), (0, 2)], Direction::Right);
+        assert_eq!(observe(&state, "a").map(|features| features.len()), Some(OBSERVATION_SIZE));
+        assert_eq!(observe(&state, "b"), None);
+    }
+
+    #[test]
+    fn rays_see_walls_bodies_and_food(){
+        let mut state = state(5, 5);
+        place(&mut state, "a", &[(1, 2), (0, 2)], Direction::Right);
+        place(&mut state, "b", &[(1, 4), (2, 4)], Direction::Left);
+        state.foods.push(Position::new(3, 2));
+        let features = observe(&state, "a").unwrap();
+        // 上: 第2格是b, 第3格出界
+        assert_eq!(features[0..3], [1. / 3., 1. / 2., 0.]);
+        // 右: 第2格是食物, 第4格出界
+        assert_eq!(features[6..9], [1. / 4., 0., 1. / 2.]);
+        // 左: 第1格是自己的蛇身, 第2格出界
+        assert_eq!(features[18..21], [1. / 2., 1., 0.]);
+        // 方向按上、下、左、右编码
+        assert_eq!(features[24..28], [0., 0., 0., 1.]);
+        assert_eq!(features[28], 2. / 25.);
+    }
+
+    #[test]
+    fn grid_is_cropped_at_the_arena_edge(){
+        let mut state = state(5, 5);
+        place(&mut state, "a", &[(0, 0), (1, 0)], Direction::Left);
+        place(&mut state, "b", &[(0, 1), (0, 2)], Direction::Down);
+        state.foods.push(Position::new(1, 1));
+        let grid = observe_grid(&state, "a", 1).unwrap();
+        assert_eq!(grid, vec![
+            GRID_WALL, GRID_WALL, GRID_WALL,
+            GRID_WALL, GRID_SELF, GRID_SELF,
+            GRID_WALL, GRID_OTHER, GRID_FOOD,
+        ]);
+    }
+}
@@ -0,0 +1,95 @@
+//! 网络消息编码
+//!
+//! 所有消息都装在[`Envelope`]中发送。信封的格式永远不变, 消息格式改变以后双方仍然能先读出协议版本,
+//! 再决定是继续处理还是断开连接。
+
+use bincode::Options;
+use serde::{Serialize, Deserialize, de::DeserializeOwned};
+use thiserror::Error;
+
+/// 网络协议版本, 消息格式有变化时加1
+pub const PROTOCOL_VERSION: u32 = 10;
+/// 服务器仍然接受的最低客户端协议版本
+///
+/// 新版本只在旧消息后面增加了内容、旧客户端还能正常游戏时, 可以不提高这个版本
+pub const MIN_PROTOCOL_VERSION: u32 = 10;
+
+/// 消息信封
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Envelope{
+    pub version: u32,
+    /// 整数使用变长编码的bincode消息
+    pub payload: Vec<u8>,
+}
+
+/// 消息的编码方式, 整数使用变长编码减少流量
+fn payload_options() -> impl Options{
+    bincode::DefaultOptions::new()
+}
+
+#[derive(Error, Debug)]
+pub enum ProtocolError{
+    #[error("消息格式错误")]
+    Malformed(#[from] bincode::Error),
+    #[error("协议版本{0}不兼容, 服务器支持{MIN_PROTOCOL_VERSION}~{PROTOCOL_VERSION}, 请刷新页面")]
+    Incompatible(u32),
+}
+
+/// 检查对方的协议版本是否兼容
+pub fn check_version(version: u32) -> Result<(), ProtocolError>{
+    if (MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&version){
+        Ok(())
+    }else{
+        Err(ProtocolError::Incompatible(version))
+    }
+}
+
+/// 把消息装进信封并编码
+pub fn encode<T: Serialize>(message: &T) -> Vec<u8>{
+    let envelope = Envelope{
+        version: PROTOCOL_VERSION,
+        payload: payload_options().serialize(message).unwrap(),
+    };
+    bincode::serialize(&envelope).unwrap()
+}
+
+/// 解码信封, 版本兼容时再解码其中的消息
+pub fn decode<T: DeserializeOwned>(data: &[u8]) -> Result<T, ProtocolError>{
+    let envelope = bincode::deserialize::<Envelope>(data)?;
+    check_version(envelope.version)?;
+    Ok(payload_options().deserialize(&envelope.payload)?)
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use crate::MessageFromClient;
+
+    #[test]
+    fn round_trip_with_the_same_version(){
+        let data = encode(&MessageFromClient::JoinGame("小蛇".to_string()));
+        let message = decode::<MessageFromClient>(&data).unwrap();
+        assert!(matches!(message, MessageFromClient::JoinGame(name) if name == "小蛇"));
+    }
+
+    #[test]
+    fn too_old_version_is_rejected_with_a_readable_error(){
+        let envelope = Envelope{
+            version: MIN_PROTOCOL_VERSION - 1,
+            payload: payload_options().serialize(&MessageFromClient::LeaveGame).unwrap(),
+        };
+        let data = bincode::serialize(&envelope).unwrap();
+        let err = decode::<MessageFromClient>(&data).unwrap_err();
+        assert!(matches!(err, ProtocolError::Incompatible(version) if version == MIN_PROTOCOL_VERSION - 1));
+        assert!(err.to_string().contains("请刷新页面"));
+    }
+
+    #[test]
+    fn garbage_is_malformed(){
+        assert!(matches!(decode::<MessageFromClient>(&[0xff; 3]), Err(ProtocolError::Malformed(_))));
+        // 信封正确但是消息无法解码
+        let envelope = Envelope{ version: PROTOCOL_VERSION, payload: vec![0xff; 3] };
+        let data = bincode::serialize(&envelope).unwrap();
+        assert!(matches!(decode::<MessageFromClient>(&data), Err(ProtocolError::Malformed(_))));
+    }
+}
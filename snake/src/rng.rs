@@ -0,0 +1,56 @@
+//! 游戏中使用的随机数
+//!
+//! 所有出生点和食物位置都从[`GameRng`]中取随机数, 同一个种子加上同样的玩家输入可以完整重现一局游戏。
+
+use rand::{RngCore, SeedableRng, random};
+use rand_chacha::ChaCha8Rng;
+
+/// 可以设置种子的随机数生成器
+#[derive(Debug, Clone)]
+pub struct GameRng{
+    seed: u64,
+    rng: ChaCha8Rng,
+}
+
+impl GameRng{
+    pub fn new(seed: u64) -> Self{
+        Self{
+            seed,
+            rng: ChaCha8Rng::seed_from_u64(seed),
+        }
+    }
+
+    /// 随机选择一个种子
+    pub fn from_entropy() -> Self{
+        Self::new(random())
+    }
+
+    /// 创建时使用的种子
+    pub fn seed(&self) -> u64{
+        self.seed
+    }
+}
+
+impl Default for GameRng{
+    fn default() -> Self {
+        Self::from_entropy()
+    }
+}
+
+impl RngCore for GameRng{
+    fn next_u32(&mut self) -> u32 {
+        self.rng.next_u32()
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.rng.next_u64()
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        self.rng.fill_bytes(dest)
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> {
+        self.rng.try_fill_bytes(dest)
+    }
+}
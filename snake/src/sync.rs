@@ -0,0 +1,312 @@
+//! 增量同步
+//!
+//! 服务器每个移动周期只发送和上一次同步相比的变化([`SyncDelta`]), 每隔[`KEYFRAME_INTERVAL`]个移动周期,
+//! 或者有客户端请求时发送一次完整的关键帧([`SyncData`])。蛇使用数字id代替uuid。
+//!
+//! 每个同步消息都带有移动周期和序号, 序号每发送一次加1。客户端丢弃过期的消息,
+//! 发现序号不连续时说明丢失了增量, 需要重新请求关键帧。
+//! 其他服务器消息也装在[`ServerFrame`](crate::ServerFrame)中带着移动周期, 客户端用来估计延迟。
+
+use std::collections::{BTreeMap, BTreeSet};
+use serde::{Serialize, Deserialize};
+
+use crate::{GameState, Position};
+
+/// 关键帧间隔(移动周期数)
+pub const KEYFRAME_INTERVAL: u64 = 50;
+
+/// 关键帧: 所有蛇和食物的完整数据
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct SyncData{
+    /// 这个状态对应的移动周期
+    pub tick: u64,
+    /// 同步消息序号
+    pub seq: u64,
+    /// (蛇的数字id, 玩家名字, 蛇身坐标)
+    pub players: Vec<(u32, String, Vec<Position>)>,
+    pub foods: Vec<Position>,
+}
+
+/// 一条蛇向前移动了一格
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct SnakeDelta{
+    pub id: u32,
+    /// 新的蛇头
+    pub head: Position,
+    /// 蛇尾是否移除, 蛇长大时保留蛇尾
+    pub tail_removed: bool,
+}
+
+/// 增量: 和上一次同步相比的变化
+#[derive(Clone, Serialize, Deserialize, Debug, Default)]
+pub struct SyncDelta{
+    pub tick: u64,
+    /// 序号比上一个同步消息大1时才能应用
+    pub seq: u64,
+    pub moved: Vec<SnakeDelta>,
+    /// 新加入或者重生的蛇, 包含玩家名字和完整的蛇身
+    pub spawned: Vec<(u32, String, Vec<Position>)>,
+    /// 离开游戏的蛇
+    pub removed: Vec<u32>,
+    pub foods_added: Vec<Position>,
+    pub foods_removed: Vec<Position>,
+}
+
+/// 收到的同步消息和当前状态的关系
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SyncOrder{
+    /// 紧接着当前状态, 可以应用
+    Next,
+    /// 重复或者比当前状态旧, 丢弃
+    Stale,
+    /// 中间丢失了missing个同步消息
+    Gap{ missing: u64 },
+}
+
+/// 客户端看到的游戏状态, 服务器用它计算增量, 客户端在它上面应用关键帧和增量
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SyncState{
+    pub tick: u64,
+    pub seq: u64,
+    pub snakes: BTreeMap<u32, Vec<Position>>,
+    /// 每条蛇的玩家名字
+    pub names: BTreeMap<u32, String>,
+    pub foods: BTreeSet<Position>,
+}
+
+impl SyncState{
+    pub fn from_world(world: &GameState) -> Self{
+        Self{
+            tick: world.tick,
+            seq: 0,
+            snakes: world.snakes.values().map(|snake| (snake.net_id, snake.body.clone())).collect(),
+            names: world.snakes.values().map(|snake| (snake.net_id, snake.player_name.clone())).collect(),
+            foods: world.foods.iter().copied().collect(),
+        }
+    }
+
+    pub fn keyframe(&self) -> SyncData{
+        SyncData{
+            tick: self.tick,
+            seq: self.seq,
+            players: self.snakes.iter().map(|(id, body)| (*id, self.name(*id), body.clone())).collect(),
+            foods: self.foods.iter().copied().collect(),
+        }
+    }
+
+    /// 从当前状态变成next需要的增量
+    pub fn delta(&self, next: &SyncState) -> SyncDelta{
+        let mut delta = SyncDelta{ tick: next.tick, seq: next.seq, ..Default::default() };
+        for (id, body) in next.snakes.iter(){
+            match self.snakes.get(id){
+                Some(prev) if prev == body => (),
+                Some(prev) if moved_from(prev, body) => delta.moved.push(SnakeDelta{
+                    id: *id,
+                    head: body[0],
+                    tail_removed: body.len() == prev.len(),
+                }),
+                _ => delta.spawned.push((*id, next.name(*id), body.clone())),
+            }
+        }
+        delta.removed = self.snakes.keys().filter(|id| !next.snakes.contains_key(id)).copied().collect();
+        delta.foods_added = next.foods.difference(&self.foods).copied().collect();
+        delta.foods_removed = self.foods.difference(&next.foods).copied().collect();
+        delta
+    }
+
+    /// 蛇的玩家名字
+    pub fn name(&self, id: u32) -> String{
+        self.names.get(&id).cloned().unwrap_or_default()
+    }
+
+    /// 关键帧只要不比当前状态旧就可以应用
+    pub fn keyframe_order(&self, data: &SyncData) -> SyncOrder{
+        if data.seq <= self.seq{
+            SyncOrder::Stale
+        }else{
+            SyncOrder::Next
+        }
+    }
+
+    /// 增量必须紧接着当前状态
+    pub fn delta_order(&self, delta: &SyncDelta) -> SyncOrder{
+        if delta.seq <= self.seq{
+            SyncOrder::Stale
+        }else if delta.seq == self.seq + 1{
+            SyncOrder::Next
+        }else{
+            SyncOrder::Gap{ missing: delta.seq - self.seq - 1 }
+        }
+    }
+
+    pub fn apply_keyframe(&mut self, data: SyncData){
+        self.tick = data.tick;
+        self.seq = data.seq;
+        self.snakes.clear();
+        self.names.clear();
+        for (id, name, body) in data.players{
+            self.snakes.insert(id, body);
+            self.names.insert(id, name);
+        }
+        self.foods = data.foods.into_iter().collect();
+    }
+
+    pub fn apply_delta(&mut self, delta: SyncDelta){
+        self.tick = delta.tick;
+        self.seq = delta.seq;
+        for moved in delta.moved{
+            if let Some(body) = self.snakes.get_mut(&moved.id){
+                body.insert(0, moved.head);
+                if moved.tail_removed{
+                    body.pop();
+                }
+            }
+        }
+        for (id, name, body) in delta.spawned{
+            self.snakes.insert(id, body);
+            self.names.insert(id, name);
+        }
+        for id in delta.removed{
+            self.snakes.remove(&id);
+            self.names.remove(&id);
+        }
+        self.foods.extend(delta.foods_added);
+        for food in delta.foods_removed{
+            self.foods.remove(&food);
+        }
+    }
+}
+
+/// body是不是prev向前移动一格(可能同时长大了一格)得到的
+fn moved_from(prev: &[Position], body: &[Position]) -> bool{
+    if prev.is_empty() || body.is_empty(){
+        return false;
+    }
+    if body.len() == prev.len(){
+        body[1..] == prev[..prev.len() - 1]
+    }else{
+        body.len() == prev.len() + 1 && body[1..] == prev[..]
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn body(cells: &[(i32, i32)]) -> Vec<Position>{
+        cells.iter().map(|(x, y)| Position::new(*x, *y)).collect()
+    }
+
+    fn state(seq: u64, snakes: &[(u32, &[(i32, i32)])], foods: &[(i32, i32)]) -> SyncState{
+        SyncState{
+            tick: seq,
+            seq,
+            snakes: snakes.iter().map(|(id, cells)| (*id, body(cells))).collect(),
+            names: snakes.iter().map(|(id, _)| (*id, format!("p{id}"))).collect(),
+            foods: body(foods).into_iter().collect(),
+        }
+    }
+
+    /// 在prev上应用增量以后应该和next完全一样
+    fn round_trip(prev: &SyncState, next: &SyncState) -> SyncDelta{
+        let delta = prev.delta(next);
+        let mut state = prev.clone();
+        state.apply_delta(delta.clone());
+        assert_eq!(&state, next);
+        delta
+    }
+
+    #[test]
+    fn plain_move_sends_only_the_head(){
+        let prev = state(1, &[(1, &[(1, 1), (1, 0)])], &[]);
+        let next = state(2, &[(1, &[(1, 2), (1, 1)])], &[]);
+        let delta = round_trip(&prev, &next);
+        assert_eq!(delta.moved.len(), 1);
+        assert!(delta.moved[0].tail_removed);
+        assert!(delta.spawned.is_empty() && delta.removed.is_empty());
+    }
+
+    #[test]
+    fn growth_keeps_the_tail(){
+        let prev = state(1, &[(1, &[(1, 1), (1, 0)])], &[(1, 2)]);
+        let next = state(2, &[(1, &[(1, 2), (1, 1), (1, 0)])], &[]);
+        let delta = round_trip(&prev, &next);
+        assert_eq!(delta.moved.len(), 1);
+        assert!(!delta.moved[0].tail_removed);
+        assert_eq!(delta.foods_removed, body(&[(1, 2)]));
+    }
+
+    #[test]
+    fn unchanged_snake_is_not_sent(){
+        let prev = state(1, &[(1, &[(1, 1), (1, 0)])], &[]);
+        let next = state(2, &[(1, &[(1, 1), (1, 0)])], &[]);
+        let delta = round_trip(&prev, &next);
+        assert!(delta.moved.is_empty() && delta.spawned.is_empty());
+    }
+
+    #[test]
+    fn spawn_sends_the_whole_body(){
+        let prev = state(1, &[], &[]);
+        let next = state(2, &[(1, &[(3, 1), (3, 0)])], &[]);
+        let delta = round_trip(&prev, &next);
+        assert_eq!(delta.spawned, vec![(1, "p1".to_string(), body(&[(3, 1), (3, 0)]))]);
+    }
+
+    #[test]
+    fn removed_snake_is_deleted(){
+        let prev = state(1, &[(1, &[(1, 1), (1, 0)]), (2, &[(5, 1), (5, 0)])], &[]);
+        let next = state(2, &[(2, &[(5, 2), (5, 1)])], &[]);
+        let delta = round_trip(&prev, &next);
+        assert_eq!(delta.removed, vec![1]);
+    }
+
+    #[test]
+    fn respawn_uses_a_new_id(){
+        let prev = state(1, &[(1, &[(1, 5), (1, 4)])], &[]);
+        let next = state(2, &[(2, &[(7, 1), (7, 0)])], &[]);
+        let delta = round_trip(&prev, &next);
+        assert_eq!(delta.removed, vec![1]);
+        assert_eq!(delta.spawned.len(), 1);
+        assert_eq!(delta.spawned[0].0, 2);
+    }
+
+    #[test]
+    fn body_that_did_not_move_one_cell_is_resent(){
+        let prev = state(1, &[(1, &[(1, 1), (1, 0)])], &[]);
+        let next = state(2, &[(1, &[(6, 6), (6, 5)])], &[]);
+        let delta = round_trip(&prev, &next);
+        assert!(delta.moved.is_empty());
+        assert_eq!(delta.spawned.len(), 1);
+    }
+
+    #[test]
+    fn foods_added_and_removed(){
+        let prev = state(1, &[], &[(1, 1), (2, 2)]);
+        let next = state(2, &[], &[(2, 2), (3, 3)]);
+        let delta = round_trip(&prev, &next);
+        assert_eq!(delta.foods_added, body(&[(3, 3)]));
+        assert_eq!(delta.foods_removed, body(&[(1, 1)]));
+    }
+
+    #[test]
+    fn keyframe_replaces_the_state(){
+        let prev = state(1, &[(1, &[(1, 1), (1, 0)])], &[(4, 4)]);
+        let next = state(5, &[(2, &[(2, 2), (2, 1)])], &[(3, 3)]);
+        let mut state = prev.clone();
+        assert_eq!(state.keyframe_order(&next.keyframe()), SyncOrder::Next);
+        state.apply_keyframe(next.keyframe());
+        assert_eq!(state, next);
+        assert_eq!(state.keyframe_order(&prev.keyframe()), SyncOrder::Stale);
+        assert_eq!(state.keyframe_order(&next.keyframe()), SyncOrder::Stale);
+    }
+
+    #[test]
+    fn delta_order(){
+        let current = state(5, &[], &[]);
+        let delta = |seq| SyncDelta{ seq, ..Default::default() };
+        assert_eq!(current.delta_order(&delta(4)), SyncOrder::Stale);
+        assert_eq!(current.delta_order(&delta(5)), SyncOrder::Stale);
+        assert_eq!(current.delta_order(&delta(6)), SyncOrder::Next);
+        assert_eq!(current.delta_order(&delta(9)), SyncOrder::Gap{ missing: 3 });
+    }
+}
@@ -1,31 +1,52 @@
-use std::time::Duration;
+use std::{cell::{Cell, RefCell}, rc::Rc, sync::{Arc, atomic::{AtomicU32, Ordering}}, time::Duration};
 
-use bevy::{prelude::*, app::ScheduleRunnerSettings, window::PresentMode, time::FixedTimestep};
+use bevy::{prelude::*, app::ScheduleRunnerSettings, window::PresentMode};
 // use bevy_inspector_egui::WorldInspectorPlugin;
 use futures_channel::mpsc::{unbounded, UnboundedSender, UnboundedReceiver};
 use js_sys::Array;
 use snake::*;
 use anyhow::Result;
 use wasm_bindgen::{prelude::*, JsCast};
-use web_sys::{WebSocket, MessageEvent, ErrorEvent};
+use web_sys::{WebSocket, MessageEvent, ErrorEvent, CloseEvent};
 
 #[wasm_bindgen]
 extern "C" {
     fn alert(s: &str);
     fn setInterval(closure: &Closure<dyn FnMut()>, millis: u32) -> f64;
+    fn setTimeout(closure: &Closure<dyn FnMut()>, millis: u32) -> f64;
     fn clearInterval(token: f64);
 }
-#[wasm_bindgen(inline_js = "export function open_dialog() { $('#exampleModal').modal('toggle'); }")]
+#[wasm_bindgen(inline_js = "export function open_dialog() { $('#exampleModal').modal('show'); }")]
 extern "C" {
     fn open_dialog();
 }
-#[wasm_bindgen(inline_js = "export function close_dialog() { $('#exampleModal').modal('toggle'); }")]
+#[wasm_bindgen(inline_js = "export function close_dialog() { $('#exampleModal').modal('hide'); }")]
 extern "C" {
     fn close_dialog();
 }
-#[wasm_bindgen(inline_js = "export function set_join_game_callback(cb) { window.joinGame = function(name){  cb(name); }; }")]
+#[wasm_bindgen(inline_js = "export function set_join_game_callback(cb) { window.joinGame = function(name, room){  cb(name, room); }; }")]
 extern "C" {
-    fn set_join_game_callback(f: &Closure<dyn Fn(String)>);
+    fn set_join_game_callback(f: &Closure<dyn Fn(String, String)>);
+}
+#[wasm_bindgen(inline_js = r#"
+    export function open_death_dialog(name, text) { showDeathDialog(name, text); }
+    export function set_respawn_callback(cb) { window.respawn = function(name){ cb(name); }; }
+"#)]
+extern "C" {
+    fn open_death_dialog(name: &str, text: &str);
+    fn set_respawn_callback(f: &Closure<dyn Fn(String)>);
+}
+#[wasm_bindgen(inline_js = "export function add_kill_feed(text) { addKillFeed(text); }")]
+extern "C" {
+    fn add_kill_feed(text: &str);
+}
+#[wasm_bindgen(inline_js = r#"
+    export function update_room_list(ids, names) {
+        updateRoomList(ids, names);
+    }
+"#)]
+extern "C" {
+    fn update_room_list(ids: Array, names: Array);
 }
 #[wasm_bindgen(inline_js = r#"
     export function update_leader_board(names, scores) {
@@ -35,6 +56,38 @@ extern "C" {
 extern "C" {
     fn update_leader_board(names: Array, scores: Array);
 }
+#[wasm_bindgen(inline_js = "export function get_query_param(name) { return new URLSearchParams(window.location.search).get(name); }")]
+extern "C" {
+    fn get_query_param(name: &str) -> Option<String>;
+}
+#[wasm_bindgen(inline_js = r#"
+    export function get_body_data(name) { return document.body.dataset[name] || null; }
+    export function default_server_url() {
+        let url = new URL('ws', window.location.href);
+        url.protocol = window.location.protocol == 'https:' ? 'wss:' : 'ws:';
+        return url.href;
+    }
+"#)]
+extern "C" {
+    fn get_body_data(name: &str) -> Option<String>;
+    fn default_server_url() -> String;
+}
+#[wasm_bindgen(inline_js = r#"
+    export function save_session(token) { sessionStorage.setItem('snake_session', token); }
+    export function load_session() { return sessionStorage.getItem('snake_session'); }
+"#)]
+extern "C" {
+    fn save_session(token: &str);
+    fn load_session() -> Option<String>;
+}
+#[wasm_bindgen(inline_js = "export function set_connection_status(state, text) { setConnectionStatus(state, text); }")]
+extern "C" {
+    fn set_connection_status(state: &str, text: &str);
+}
+#[wasm_bindgen(inline_js = "export function set_server_name(name) { document.title = name; }")]
+extern "C" {
+    fn set_server_name(name: &str);
+}
 
 #[wasm_bindgen(start)]
 pub fn start() {
@@ -48,6 +101,63 @@ pub fn start() {
 #[derive(Resource, Default, Deref, DerefMut)]
 pub struct CurrentPlayer(Option<String>);
 
+/// 当前玩家蛇头的颜色, 由服务器分配
+#[derive(Resource, Deref, DerefMut)]
+pub struct CurrentPlayerColor(Color);
+
+/// 当前玩家的蛇的数字id, 加入游戏后由服务器发送
+#[derive(Resource, Default, Deref, DerefMut)]
+pub struct CurrentSnake(Option<u32>);
+
+/// 从服务器同步的游戏状态
+#[derive(Resource, Default)]
+pub struct SyncedWorld{
+    pub state: SyncState,
+    /// 收到第一个关键帧之前忽略增量
+    pub has_keyframe: bool,
+}
+
+/// 估计的服务器消息延迟(毫秒), 连接收到消息时更新
+#[derive(Resource, Clone, Default)]
+pub struct ServerLag(Arc<AtomicU32>);
+
+impl ServerLag{
+    pub fn get(&self) -> u32{
+        self.0.load(Ordering::Relaxed)
+    }
+
+    fn set(&self, millis: u32){
+        self.0.store(millis, Ordering::Relaxed);
+    }
+}
+
+/// 观众模式: 网址中带有?spectate或者?follow时只观看不加入游戏, 可以跟随一个玩家
+///
+/// ?spectate&room=房间id 观看指定的房间, ?follow=玩家名字 跟随这个玩家,
+/// Tab键切换跟随的玩家, Esc键观看整个场地
+#[derive(Resource, Default)]
+pub struct Spectator{
+    pub enabled: bool,
+    pub room: Option<u32>,
+    /// 跟随的玩家名字, 玩家死亡后重新加入时继续跟随
+    pub following: Option<String>,
+}
+
+impl Spectator{
+    fn from_url() -> Self{
+        let following = get_query_param("follow").filter(|name| !name.trim().is_empty());
+        Self{
+            enabled: get_query_param("spectate").is_some() || following.is_some(),
+            room: get_query_param("room").and_then(|room| room.parse().ok()),
+            following,
+        }
+    }
+}
+
+/// 按住方向键时重复发送的间隔, 和服务器的移动周期一致
+#[derive(Resource, Deref, DerefMut)]
+pub struct InputTimer(Timer);
+
 fn start_game() -> Result<()> {
     info!("start game...");
     // 启动游戏
@@ -59,6 +169,14 @@ fn start_game() -> Result<()> {
     .insert_resource(ClearColor(Color::rgb(0.04, 0.04, 0.04)))
     .insert_resource(PlayerList::default())
     .insert_resource(CurrentPlayer::default())
+    .insert_resource(CurrentSnake::default())
+    .insert_resource(SyncedWorld::default())
+    .insert_resource(MessageBacklog::default())
+    .insert_resource(Spectator::from_url())
+    // 连接成功后由服务器下发
+    .insert_resource(GameConfig::default())
+    .insert_resource(CurrentPlayerColor(SNAKE_HEAD_COLOR_CURRENT))
+    .insert_resource(InputTimer(Timer::from_seconds(GameConfig::default().tick_interval, TimerMode::Once)))
     // 窗口设置
     .add_plugins(DefaultPlugins.set(WindowPlugin {
         window: WindowDescriptor {
@@ -74,16 +192,14 @@ fn start_game() -> Result<()> {
         CoreStage::PostUpdate,
         SystemSet::new()
             .with_system(position_translation)
-            .with_system(size_scaling),
+            .with_system(size_scaling)
+            .with_system(follow_camera.after(position_translation)),
     )
     // .add_plugin(WorldInspectorPlugin::new())
     .add_startup_system(camera_setup)
-    .add_system_set(
-        SystemSet::new()
-            .with_run_criteria(FixedTimestep::step(0.08))
-            .with_system(recive_message),
-    )
+    .add_system(recive_message)
     .add_system(snake_movement_input)
+    .add_system(spectator_input)
     .run();
     info!("游戏结束...");
 
@@ -91,41 +207,109 @@ fn start_game() -> Result<()> {
 }
 
 pub fn snake_movement_input(
+    time: Res<Time>,
     keyboard_input: Res<Input<KeyCode>>,
     message_sender: Res<MessageSender>,
+    mut input_timer: ResMut<InputTimer>,
+    spectator: Res<Spectator>,
     current_player: Res<CurrentPlayer>) {
+
+    // 观众不控制蛇
+    if spectator.enabled{
+        return;
+    }
+    input_timer.tick(time.delta());
     
-    let send_key_msg = |key:&str|{
-        if let Some(player) = current_player.0.as_ref(){
-            let _ = message_sender.unbounded_send(IncomingMessage::ClientMessage(MessageFromClient::KeyEvent((player.clone(), key.to_string()))));
+    let send_key_msg = |direction: &snake::Direction|{
+        if current_player.0.is_some(){
+            let _ = message_sender.unbounded_send(IncomingMessage::ClientMessage(MessageFromClient::Turn(*direction)));
         }
     };
 
-    if keyboard_input.pressed(KeyCode::Left) {
-        send_key_msg("L");
-    } else if keyboard_input.pressed(KeyCode::Down) {
-        send_key_msg("D");
-    } else if keyboard_input.pressed(KeyCode::Up) {
-        send_key_msg("U");
-    } else if keyboard_input.pressed(KeyCode::Right) {
-        send_key_msg("R");
+    let keys = [
+        (KeyCode::Left, snake::Direction::Left),
+        (KeyCode::Down, snake::Direction::Down),
+        (KeyCode::Up, snake::Direction::Up),
+        (KeyCode::Right, snake::Direction::Right),
+    ];
+
+    // 刚按下的键立即发送, 按住不放时每个移动周期最多发送一次
+    let key = keys.iter().find(|(code, _)| keyboard_input.just_pressed(*code))
+        .or_else(|| {
+            if input_timer.finished(){
+                keys.iter().find(|(code, _)| keyboard_input.pressed(*code))
+            }else{
+                None
+            }
+        });
+
+    if let Some((_, direction)) = key{
+        send_key_msg(direction);
+        input_timer.reset();
     }
 }
 
+/// 处理所有收到的消息
+///
+/// 积压了多条同步消息时, 最后一个关键帧之前的同步消息直接丢弃,
+/// 之后的增量依次应用到同步状态上, 最后只更新一次实体。
 fn recive_message(
     mut message_receiver: ResMut<MessageReceiver>,
+    mut backlog: ResMut<MessageBacklog>,
     message_sender: Res<MessageSender>,
     mut player_list: ResMut<PlayerList>,
     mut current_player: ResMut<CurrentPlayer>,
+    mut current_color: ResMut<CurrentPlayerColor>,
+    mut input_timer: ResMut<InputTimer>,
+    mut game_config: ResMut<GameConfig>,
+    mut synced_world: ResMut<SyncedWorld>,
+    mut current_snake: ResMut<CurrentSnake>,
+    spectator: Res<Spectator>,
+    server_lag: Res<ServerLag>,
     mut positions: Query<&mut Position>,
     foods: Query<Entity, With<Food>>,
     mut commands: Commands){
-    if let Ok(Some(msg)) = message_receiver.try_next(){
+    let messages = message_receiver.drain(&mut backlog);
+    let last_keyframe = messages.iter()
+        .rposition(|msg| matches!(msg, IncomingMessage::ServerMessage(MessageFromServer::SyncData(_))));
+    let mut changed = false;
+    // 换房间时删除的食物, 实体在这一帧结束时才真正删除, 更新实体时要跳过
+    let mut removed_foods = vec![];
+    for (idx, msg) in messages.into_iter().enumerate(){
+        let is_sync = matches!(msg, IncomingMessage::ServerMessage(MessageFromServer::SyncData(_) | MessageFromServer::SyncDelta(_)));
+        if is_sync && last_keyframe.map(|last| idx < last).unwrap_or(false){
+            continue;
+        }
         match msg {
-            IncomingMessage::ServerMessage(MessageFromServer::OnConnected(id)) => {
-                info!("连接成功! uuid={id}");
-                current_player.0.replace(id);
-                open_dialog();
+            IncomingMessage::ServerMessage(MessageFromServer::OnConnected(welcome)) => {
+                info!("连接成功! {:?}", welcome);
+                current_player.0.replace(welcome.player_id);
+                let [r, g, b] = welcome.color;
+                **current_color = Color::rgb(r, g, b);
+                input_timer.set_duration(Duration::from_secs_f32(welcome.config.tick_interval));
+                set_server_name(&welcome.server_name);
+                *game_config = welcome.config;
+                // 刷新页面或者断线后用这个令牌恢复会话
+                save_session(&welcome.session_token);
+                if welcome.resumed{
+                    info!("恢复了之前的会话");
+                    // 观众重新申请名额, 断线期间名额可能已经被别人占用
+                    if spectator.enabled{
+                        let _ = message_sender.unbounded_send(IncomingMessage::ClientMessage(MessageFromClient::Spectate));
+                    }
+                    continue;
+                }
+                // 请求关键帧, 之后才能应用增量
+                let _ = message_sender.unbounded_send(IncomingMessage::ClientMessage(MessageFromClient::RequestKeyframe));
+                if spectator.enabled{
+                    let _ = message_sender.unbounded_send(IncomingMessage::ClientMessage(MessageFromClient::Spectate));
+                    if let Some(room) = spectator.room{
+                        let _ = message_sender.unbounded_send(IncomingMessage::ClientMessage(MessageFromClient::JoinRoom(room)));
+                    }
+                }else{
+                    let _ = message_sender.unbounded_send(IncomingMessage::ClientMessage(MessageFromClient::ListRooms));
+                    open_dialog();
+                }
             }
             IncomingMessage::ServerMessage(MessageFromServer::LeaderBoard(leader_board)) => {
                 info!("得分榜:{:?}", leader_board);
@@ -134,96 +318,232 @@ fn recive_message(
                 update_leader_board(js_sys::Array::from_iter(names), js_sys::Array::from_iter(scores));
             }
             IncomingMessage::ClientMessage(MessageFromClient::InputName(user_name)) => {
-                if let Some(player_id) = current_player.0.as_ref(){
-                    let msg_join = MessageFromClient::JoinGame((player_id.clone(), user_name));
+                if current_player.0.is_some(){
+                    let msg_join = MessageFromClient::JoinGame(user_name);
                     let _ = message_sender.unbounded_send(IncomingMessage::ClientMessage(msg_join));
                 }
             }
-            IncomingMessage::ServerMessage(MessageFromServer::SyncData(mut data)) => {
-                // 删除服务器不存在的玩家
-                player_list.retain(|k, v|{
-                    let contains = data.players.contains_key(k);
-                    if !contains{
-                        //删除玩家的所有实体
-                        for seg in &v.snake_segments{
-                            commands.entity(*seg).despawn();
-                        }
-                    }
-                    contains
-                });
-                // 更新玩家数据
-                for (id, player) in data.players{
-                    if !player_list.contains_key(&id){
-                        //添加玩家
-                        let player_id = id.clone();
-                        let player_info = PlayerInfo {
-                            snake_segments: vec![],
-                            player_id:player_id.clone(),
-                            player_name: player_id.clone(),
-                            spawn_pos: Position::new(0, 0),
-                            last_tail_position: None,
-                        };
-                        player_list.insert(player_id.clone(), player_info);
-                        let mut head_color = SNAKE_HEAD_COLOR;
-                        if let Some(current_id) = current_player.0.as_ref(){
-                            if current_id == &player_id{
-                                head_color = SNAKE_HEAD_COLOR_CURRENT;
-                            }
-                        }
-                        spawn_snake(&mut commands, &mut player_list, player_id, head_color);
-                    }
-                    //检查玩家是否有多余的segment
-                    let player_info = player_list.get_mut(&id).unwrap();
-                    while player.len() > 0 && player_info.snake_segments.len() > player.len() {
-                        let seg = player_info.snake_segments.pop().unwrap();
+            IncomingMessage::ServerMessage(MessageFromServer::RoomList(rooms)) => {
+                let ids = rooms.iter().map(|room| JsValue::from_f64(room.id as f64));
+                let names = rooms.iter().map(|room| JsValue::from_str(&format!("{} ({}/{})", room.name, room.players, room.max_players)));
+                update_room_list(js_sys::Array::from_iter(ids), js_sys::Array::from_iter(names));
+            }
+            IncomingMessage::ServerMessage(MessageFromServer::RoomJoined(room)) => {
+                info!("进入房间: {:?}", room);
+                // 新房间的同步消息从关键帧重新开始
+                *synced_world = SyncedWorld::default();
+                **current_snake = None;
+                // 立即删除旧房间的蛇和食物, 新房间的蛇可能使用相同的数字id
+                for (_, player) in player_list.drain(){
+                    for seg in player.snake_segments{
                         commands.entity(seg).despawn();
                     }
-                    
-                    for (idx, server_seg_pos) in player.iter().enumerate(){
-                        if let Some(client_seg) = player_info.snake_segments.iter_mut().nth(idx){
-                            if let Ok(mut pos) = positions.get_mut(*client_seg){
-                                *pos = *server_seg_pos;
-                            }
-                        }else{
-                            //长度不够，增加entity
-                            player_info.snake_segments.push(spawn_segment(&mut commands, *server_seg_pos));
-                        }
-                    }
                 }
-                //删除不存在的Food
-                for food in foods.iter(){
-                    let pos = match positions.get(food){
-                        Err(_) => continue,
-                        Ok(pos) => pos
-                    };
-                    match data.foods.binary_search(pos){
-                        Err(_) =>{
-                            commands.entity(food).despawn();
-                        }
-                        Ok(idx) => {
-                            // 已存在，不再创建Entity
-                            let _ = data.foods.remove(idx);
-                        }
-                    }
+                for food in foods.iter().filter(|food| !removed_foods.contains(food)){
+                    commands.entity(food).despawn();
+                    removed_foods.push(food);
                 }
-                //添加Food
-                for server_pos in data.foods{
-                    commands
-                    .spawn(SpriteBundle {
-                        sprite: Sprite {
-                            color: FOOD_COLOR,
-                            ..default()
-                        },
-                        ..default()
-                    })
-                    .insert(Food)
-                    .insert(server_pos)
-                    .insert(snake::Size::square(0.8));
+                changed = true;
+            }
+            IncomingMessage::ServerMessage(MessageFromServer::Spectating) => {
+                info!("观众模式");
+            }
+            // 服务器随后断开连接, 原因在Close帧中显示, 不再重连
+            IncomingMessage::ServerMessage(MessageFromServer::Rejected(reason)) => {
+                warn!("请求被拒绝: {reason}");
+            }
+            // 选择的房间转发给服务器
+            IncomingMessage::ClientMessage(msg @ (MessageFromClient::JoinRoom(_) | MessageFromClient::CreateRoom(_))) => {
+                let _ = message_sender.unbounded_send(IncomingMessage::ClientMessage(msg));
+            }
+            IncomingMessage::ServerMessage(MessageFromServer::Joined(id)) => {
+                info!("加入游戏, 蛇的id:{id}");
+                **current_snake = Some(id);
+            }
+            IncomingMessage::ServerMessage(MessageFromServer::PlayerDied { victim, killer, cause, final_length }) => {
+                info!("死亡: {:?} {:?} 长度:{final_length}", cause, killer);
+                **current_snake = None;
+                open_death_dialog(&victim, &death_text(killer.as_deref(), cause, final_length));
+            }
+            IncomingMessage::ServerMessage(MessageFromServer::KillFeed { victim, killer, cause, killer_kills }) => {
+                add_kill_feed(&kill_feed_text(&victim, killer.as_deref(), cause, killer_kills));
+            }
+            IncomingMessage::ServerMessage(MessageFromServer::SyncData(data)) => {
+                if synced_world.state.keyframe_order(&data) == SyncOrder::Stale{
+                    info!("丢弃过期的关键帧 seq={} tick={}", data.seq, data.tick);
+                    continue;
                 }
-            },
+                synced_world.state.apply_keyframe(data);
+                synced_world.has_keyframe = true;
+                changed = true;
+            }
+            IncomingMessage::ServerMessage(MessageFromServer::SyncDelta(delta)) => {
+                // 收到第一个关键帧之前无法应用增量
+                if !synced_world.has_keyframe{
+                    continue;
+                }
+                match synced_world.state.delta_order(&delta){
+                    SyncOrder::Next => (),
+                    SyncOrder::Stale => {
+                        info!("丢弃过期的增量 seq={} tick={}", delta.seq, delta.tick);
+                        continue;
+                    }
+                    SyncOrder::Gap { missing } => {
+                        // 丢失了增量, 等待新的关键帧
+                        warn!("丢失了{missing}个同步消息 seq={}~{}, 重新请求关键帧, 当前延迟{}ms", synced_world.state.seq + 1, delta.seq - 1, server_lag.get());
+                        synced_world.has_keyframe = false;
+                        let _ = message_sender.unbounded_send(IncomingMessage::ClientMessage(MessageFromClient::RequestKeyframe));
+                        continue;
+                    }
+                }
+                synced_world.state.apply_delta(delta);
+                changed = true;
+            }
             _ => ()
         }
     }
+    if changed{
+        let foods = foods.iter().filter(|food| !removed_foods.contains(food));
+        render_world(&synced_world.state, &mut player_list, &current_snake, **current_color, &mut positions, foods, &mut commands);
+    }
+}
+
+/// 观众切换跟随的玩家: Tab切换到下一个玩家, Esc观看整个场地
+fn spectator_input(
+    keyboard_input: Res<Input<KeyCode>>,
+    synced_world: Res<SyncedWorld>,
+    mut spectator: ResMut<Spectator>){
+    if !spectator.enabled{
+        return;
+    }
+    if keyboard_input.just_pressed(KeyCode::Escape){
+        spectator.following = None;
+    }
+    if keyboard_input.just_pressed(KeyCode::Tab){
+        let names = &synced_world.state.names;
+        // 按蛇的id顺序切换到下一个玩家, 没有更大的id时回到第一个
+        let current = spectator.following.as_ref().and_then(|name| following_id(&synced_world.state, name));
+        let next = match current{
+            Some(current) => names.range(current + 1..).next().or_else(|| names.iter().next()),
+            None => names.iter().next(),
+        };
+        spectator.following = next.map(|(_, name)| name.clone());
+        info!("跟随: {:?}", spectator.following);
+    }
+}
+
+/// 名字是name的玩家的蛇的数字id
+fn following_id(state: &SyncState, name: &str) -> Option<u32>{
+    state.names.iter().find(|(_, player_name)| player_name.as_str() == name).map(|(id, _)| *id)
+}
+
+/// 跟随一个玩家时镜头对准蛇头并放大, 否则显示整个场地
+fn follow_camera(
+    spectator: Res<Spectator>,
+    synced_world: Res<SyncedWorld>,
+    player_list: Res<PlayerList>,
+    heads: Query<&Transform, (With<SnakeHead>, Without<Camera>)>,
+    mut cameras: Query<(&mut Transform, &mut OrthographicProjection), With<Camera>>){
+    let head = spectator.following.as_ref()
+        .and_then(|name| following_id(&synced_world.state, name))
+        .and_then(|id| player_list.get(&id))
+        .and_then(|player| player.snake_segments.first())
+        .and_then(|entity| heads.get(*entity).ok());
+    for (mut transform, mut projection) in cameras.iter_mut(){
+        match head{
+            Some(head) => {
+                transform.translation.x = head.translation.x;
+                transform.translation.y = head.translation.y;
+                projection.scale = 0.5;
+            }
+            None => {
+                transform.translation.x = 0.;
+                transform.translation.y = 0.;
+                projection.scale = 1.;
+            }
+        }
+    }
+}
+
+/// 根据同步的状态更新所有蛇和食物的实体
+fn render_world(
+    state: &SyncState,
+    player_list: &mut ResMut<PlayerList>,
+    current_snake: &CurrentSnake,
+    current_color: Color,
+    positions: &mut Query<&mut Position>,
+    foods: impl Iterator<Item = Entity>,
+    commands: &mut Commands){
+    // 删除服务器不存在的玩家
+    player_list.retain(|k, v|{
+        let contains = state.snakes.contains_key(k);
+        if !contains{
+            //删除玩家的所有实体
+            for seg in &v.snake_segments{
+                commands.entity(*seg).despawn();
+            }
+        }
+        contains
+    });
+    // 更新玩家数据
+    for (id, player) in state.snakes.iter(){
+        if !player_list.contains_key(id){
+            //添加玩家
+            let player_info = PlayerInfo {
+                snake_segments: vec![],
+                player_id: *id,
+                player_name: state.name(*id),
+                spawn_pos: player.first().copied().unwrap_or(Position::new(0, 0)),
+                last_tail_position: None,
+            };
+            player_list.insert(*id, player_info);
+            let head_color = if **current_snake == Some(*id){ current_color }else{ SNAKE_HEAD_COLOR };
+            spawn_snake(commands, player_list, *id, head_color);
+        }
+        //检查玩家是否有多余的segment
+        let player_info = player_list.get_mut(id).unwrap();
+        while !player.is_empty() && player_info.snake_segments.len() > player.len() {
+            let seg = player_info.snake_segments.pop().unwrap();
+            commands.entity(seg).despawn();
+        }
+        
+        for (idx, server_seg_pos) in player.iter().enumerate(){
+            if let Some(client_seg) = player_info.snake_segments.iter_mut().nth(idx){
+                if let Ok(mut pos) = positions.get_mut(*client_seg){
+                    *pos = *server_seg_pos;
+                }
+            }else{
+                //长度不够，增加entity
+                player_info.snake_segments.push(spawn_segment(commands, *server_seg_pos));
+            }
+        }
+    }
+    //删除不存在的Food
+    let mut new_foods = state.foods.clone();
+    for food in foods{
+        let pos = match positions.get(food){
+            Err(_) => continue,
+            Ok(pos) => pos
+        };
+        // 已存在的不再创建Entity
+        if !new_foods.remove(pos){
+            commands.entity(food).despawn();
+        }
+    }
+    //添加Food
+    for server_pos in new_foods{
+        commands
+        .spawn(SpriteBundle {
+            sprite: Sprite {
+                color: FOOD_COLOR,
+                ..default()
+            },
+            ..default()
+        })
+        .insert(Food)
+        .insert(server_pos)
+        .insert(snake::Size::square(0.8));
+    }
 }
 
 fn setup_network(mut commands: Commands){
@@ -232,19 +552,152 @@ fn setup_network(mut commands: Commands){
     let (sender1, receiver1) = unbounded::<IncomingMessage>();
     commands.insert_resource(MessageReceiver::new(receiver));
     commands.insert_resource(MessageSender::new(sender1));
+    let server_lag = ServerLag::default();
+    commands.insert_resource(server_lag.clone());
 
     info!("连接服务器...");
-    connect_server(sender, receiver1).unwrap();
+    connect_server(sender, receiver1, server_lag).unwrap();
+}
+
+/// 第一次重连前等待的时间(毫秒), 之后每次失败加倍
+const RECONNECT_DELAY_MS: u32 = 1000;
+const MAX_RECONNECT_DELAY_MS: u32 = 30000;
+/// 连续重连失败这么多次以后放弃
+const MAX_RECONNECT_ATTEMPTS: u32 = 10;
+/// 服务器拒绝连接时使用的Close代码(Policy), 这时重连也没有用
+const CLOSE_CODE_POLICY: u16 = 1008;
+/// 消息落后这么多个移动周期时提示延迟
+const LAG_WARNING_TICKS: f64 = 3.;
+
+/// 估计服务器消息的延迟
+///
+/// 服务器每个移动周期发出消息, 从起点开始经过的时间除以移动周期就是现在应该收到的移动周期,
+/// 实际收到的移动周期落后多少就是延迟了多少。比预计更早到达的消息作为新的起点。
+struct LagMeter{
+    /// 起点: (移动周期, 收到的时间(毫秒))
+    base: Option<(u64, f64)>,
+    /// 移动周期(毫秒)
+    tick_ms: f64,
+    /// 是否已经提示了延迟
+    lagging: bool,
+}
+
+impl LagMeter{
+    fn new(tick_interval: f32) -> Self{
+        Self{ base: None, tick_ms: tick_interval as f64 * 1000., lagging: false }
+    }
+
+    /// 每个房间的移动周期不同, 换房间或者重新连接后重新选择起点
+    fn reset(&mut self){
+        self.base = None;
+    }
+
+    /// 记录收到的消息, 返回延迟了多少个移动周期
+    fn record(&mut self, tick: u64, now: f64) -> f64{
+        let (base_tick, base_time) = *self.base.get_or_insert((tick, now));
+        let expected = base_tick as f64 + (now - base_time) / self.tick_ms;
+        let lag = expected - tick as f64;
+        if lag < 0.{
+            self.base = Some((tick, now));
+        }
+        lag.max(0.)
+    }
+}
+
+/// 死亡弹窗中显示的文字
+fn death_text(killer: Option<&str>, cause: DeathCause, final_length: usize) -> String{
+    let reason = match (cause, killer){
+        (DeathCause::Wall, _) => "撞到了墙".to_string(),
+        (DeathCause::SelfCollision, _) => "撞到了自己".to_string(),
+        (DeathCause::Collision, Some(killer)) => format!("撞到了[{killer}]的身体"),
+        (DeathCause::HeadOn, Some(killer)) => format!("和[{killer}]迎头相撞"),
+        (DeathCause::Collision | DeathCause::HeadOn, None) => "撞到了其他蛇".to_string(),
+    };
+    format!("你{reason}, 最终长度: {final_length}")
+}
+
+/// 击杀信息中显示的文字
+fn kill_feed_text(victim: &str, killer: Option<&str>, cause: DeathCause, killer_kills: u32) -> String{
+    match (cause, killer){
+        (DeathCause::Wall, _) => format!("[{victim}] 撞墙了"),
+        (DeathCause::SelfCollision, _) => format!("[{victim}] 撞到了自己"),
+        (DeathCause::Collision, Some(killer)) if killer_kills > 0 => format!("[{killer}] 截杀了 [{victim}] (击杀{killer_kills})"),
+        (DeathCause::HeadOn, Some(killer)) if killer_kills > 0 => format!("[{killer}] 迎头撞死了 [{victim}] (击杀{killer_kills})"),
+        // 凶手同时死亡
+        (_, Some(killer)) => format!("[{victim}] 和 [{killer}] 同归于尽"),
+        (_, None) => format!("[{victim}] 死亡"),
+    }
+}
+
+/// 服务器地址, 优先使用页面参数 ?server=, 其次是index.html中body的data-server-url,
+/// 都没有时连接页面所在主机的ws路径(https页面使用wss)
+fn server_url() -> String{
+    get_query_param("server")
+        .or_else(|| get_body_data("serverUrl"))
+        .filter(|url| !url.trim().is_empty())
+        .unwrap_or_else(default_server_url)
+}
+
+/// 到服务器的连接, 断开后自动重连
+struct Connection{
+    url: String,
+    socket: RefCell<Option<WebSocket>>,
+    /// 连续重连的次数, 连接成功后清零
+    attempts: Cell<u32>,
+    /// 收到的服务器消息转发给游戏
+    sender: UnboundedSender<IncomingMessage>,
+    lag_meter: RefCell<LagMeter>,
+    server_lag: ServerLag,
+}
+
+impl Connection{
+    /// 用消息的移动周期更新延迟, 延迟超过LAG_WARNING_TICKS个移动周期时提示
+    fn record_lag(&self, frame: &ServerFrame){
+        let mut meter = self.lag_meter.borrow_mut();
+        match &frame.message{
+            MessageFromServer::OnConnected(welcome) => {
+                *meter = LagMeter::new(welcome.config.tick_interval);
+                self.server_lag.set(0);
+            }
+            MessageFromServer::RoomJoined(_) => meter.reset(),
+            _ => ()
+        }
+        let tick = match frame.tick{
+            Some(v) => v,
+            None => return
+        };
+        let lag = meter.record(tick, js_sys::Date::now());
+        let millis = (lag * meter.tick_ms) as u32;
+        self.server_lag.set(millis);
+        let lagging = lag >= LAG_WARNING_TICKS;
+        if lagging != meter.lagging{
+            meter.lagging = lagging;
+            if lagging{
+                warn!("服务器消息延迟{millis}ms, 落后{lag:.1}个移动周期 tick={tick}");
+                set_connection_status("connected", &format!("已连接, 延迟{millis}ms"));
+            }else{
+                info!("服务器消息延迟恢复正常");
+                set_connection_status("connected", "已连接");
+            }
+        }
+    }
 }
 
-pub fn connect_server(sender: UnboundedSender<IncomingMessage>, mut receiver: UnboundedReceiver<IncomingMessage>) -> Result<(), JsValue> {
-    let ws = WebSocket::new("wss://www.ccfish.run/snake/ws")?;
+pub fn connect_server(sender: UnboundedSender<IncomingMessage>, mut receiver: UnboundedReceiver<IncomingMessage>, server_lag: ServerLag) -> Result<(), JsValue> {
+    let connection = Rc::new(Connection{
+        url: server_url(),
+        socket: RefCell::new(None),
+        attempts: Cell::new(0),
+        sender: sender.clone(),
+        lag_meter: RefCell::new(LagMeter::new(GameConfig::default().tick_interval)),
+        server_lag,
+    });
 
     //加入游戏
     let sender_clone = sender.clone();
-    let closure = Closure::new(move |name:String| {
+    let closure = Closure::new(move |name:String, room: String| {
         info!("加入游戏! {name}");
-        if name.trim().len() == 0{
+        if name.trim().is_empty(){
             alert("请输入名字!");
             return;
         }
@@ -255,22 +708,39 @@ pub fn connect_server(sender: UnboundedSender<IncomingMessage>, mut receiver: Un
         }
         //关闭对话框
         close_dialog();
+        // 没有选择房间时创建新房间
+        let msg_room = match room.parse::<u32>(){
+            Ok(room_id) => MessageFromClient::JoinRoom(room_id),
+            Err(_) => MessageFromClient::CreateRoom(format!("{}的房间", name.trim())),
+        };
+        let _ = sender_clone.unbounded_send(IncomingMessage::ClientMessage(msg_room));
         let _ = sender_clone.unbounded_send(IncomingMessage::ClientMessage(MessageFromClient::InputName(name)));
     });
     set_join_game_callback(&closure);
     closure.forget();
 
-    let cloned_ws = ws.clone();
+    //死亡后重新加入游戏
+    let sender_clone = sender.clone();
+    let closure = Closure::new(move |name:String| {
+        let _ = sender_clone.unbounded_send(IncomingMessage::ClientMessage(MessageFromClient::InputName(name)));
+    });
+    set_respawn_callback(&closure);
+    closure.forget();
 
+    let cloned_connection = connection.clone();
     let closure = Closure::new(move || {
-        //检查是否有消息要发送
-        if let Ok(Some(msg)) = receiver.try_next(){
+        //发送所有待发送的消息, 断线期间的消息直接丢弃
+        while let Ok(Some(msg)) = receiver.try_next(){
             match msg{
                 IncomingMessage::ClientMessage(msg) => {
                     //消息发送给服务器端
                     // info!("有消息发送给服务器端:{:?}", msg);
-                    let data = bincode::serialize(&msg).unwrap();
-                    let _ = cloned_ws.send_with_u8_array(&data);
+                    if let Some(ws) = cloned_connection.socket.borrow().as_ref(){
+                        if ws.ready_state() == WebSocket::OPEN{
+                            let data = encode(&msg);
+                            let _ = ws.send_with_u8_array(&data);
+                        }
+                    }
                 },
                 _ => ()
             }
@@ -279,18 +749,29 @@ pub fn connect_server(sender: UnboundedSender<IncomingMessage>, mut receiver: Un
     let _token = setInterval(&closure, 10);
     closure.forget();
 
+    set_connection_status("connecting", "正在连接服务器...");
+    open_socket(&connection)
+}
+
+/// 创建WebSocket并设置回调
+fn open_socket(connection: &Rc<Connection>) -> Result<(), JsValue> {
+    info!("连接 {}", connection.url);
+    let ws = WebSocket::new(&connection.url)?;
     ws.set_binary_type(web_sys::BinaryType::Arraybuffer);
 
+    let cloned_connection = connection.clone();
     let onmessage_callback = Closure::<dyn FnMut(_)>::new(move |e: MessageEvent| {
         if let Ok(abuf) = e.data().dyn_into::<js_sys::ArrayBuffer>() {
             let array = js_sys::Uint8Array::new(&abuf);
             let data = array.to_vec();
             // info!("接收到二进制数据 长度={}", data.len());
-            if let Ok(msg) = bincode::deserialize::<MessageFromServer>(&data){
-                // info!("接收到服务器消息:{:?}", msg);
-                let _ = sender.unbounded_send(IncomingMessage::ServerMessage(msg));
-            }else{
-                info!("接收到其他消息:{:?}", data.len());
+            match decode::<ServerFrame>(&data){
+                Ok(frame) => {
+                    // info!("接收到服务器消息:{:?}", frame);
+                    cloned_connection.record_lag(&frame);
+                    let _ = cloned_connection.sender.unbounded_send(IncomingMessage::ServerMessage(frame.message));
+                }
+                Err(err) => info!("无法解析服务器消息:{err} 长度={}", data.len())
             }
         }
     });
@@ -305,12 +786,58 @@ pub fn connect_server(sender: UnboundedSender<IncomingMessage>, mut receiver: Un
     ws.set_onerror(Some(onerror_callback.as_ref().unchecked_ref()));
     onerror_callback.forget();
 
-    let onopen_callback = Closure::<dyn FnMut()>::new(move || {
-        //输入姓名
+    // 服务器拒绝连接时, 原因在Close帧中, 不再重连; 其他原因断开时自动重连
+    let cloned_connection = connection.clone();
+    let onclose_callback = Closure::<dyn FnMut(_)>::new(move |e: CloseEvent| {
+        info!("连接断开: code={} reason={}", e.code(), e.reason());
+        cloned_connection.socket.borrow_mut().take();
+        if e.code() == CLOSE_CODE_POLICY{
+            set_connection_status("failed", &format!("连接被拒绝: {}", e.reason()));
+            if !e.reason().is_empty(){
+                alert(&e.reason());
+            }
+            return;
+        }
+        schedule_reconnect(&cloned_connection);
+    });
+    ws.set_onclose(Some(onclose_callback.as_ref().unchecked_ref()));
+    onclose_callback.forget();
 
+    let cloned_connection = connection.clone();
+    let cloned_ws = ws.clone();
+    let onopen_callback = Closure::<dyn FnMut()>::new(move || {
+        cloned_connection.attempts.set(0);
+        set_connection_status("connected", "已连接");
+        //发送协议版本和上一次的会话令牌, 服务器检查通过后回复OnConnected, 然后输入姓名
+        let data = encode(&MessageFromClient::Hello { version: PROTOCOL_VERSION, session: load_session() });
+        let _ = cloned_ws.send_with_u8_array(&data);
     });
     ws.set_onopen(Some(onopen_callback.as_ref().unchecked_ref()));
     onopen_callback.forget();
 
+    connection.socket.borrow_mut().replace(ws);
     Ok(())
-}
\ No newline at end of file
+}
+
+/// 等待一段时间后重连, 等待时间每次加倍
+fn schedule_reconnect(connection: &Rc<Connection>){
+    let attempts = connection.attempts.get() + 1;
+    if attempts > MAX_RECONNECT_ATTEMPTS{
+        set_connection_status("failed", "无法连接服务器, 请刷新页面");
+        return;
+    }
+    connection.attempts.set(attempts);
+    let delay = RECONNECT_DELAY_MS.saturating_mul(1 << (attempts - 1).min(5)).min(MAX_RECONNECT_DELAY_MS);
+    set_connection_status("reconnecting", &format!("连接断开, {}秒后第{attempts}次重新连接...", delay / 1000));
+
+    let cloned_connection = connection.clone();
+    let closure = Closure::<dyn FnMut()>::new(move || {
+        set_connection_status("connecting", "正在重新连接...");
+        if let Err(err) = open_socket(&cloned_connection){
+            error!("重新连接失败: {:?}", err);
+            schedule_reconnect(&cloned_connection);
+        }
+    });
+    setTimeout(&closure, delay);
+    closure.forget();
+}